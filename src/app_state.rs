@@ -1,57 +1,107 @@
-use std::{collections::HashMap, sync::Arc};
-use tokio::sync::Mutex;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+use tokio::sync::{Mutex, Semaphore, mpsc::UnboundedSender};
+use tokio_tungstenite::tungstenite::Message;
 use tracing::error;
+use uuid::Uuid;
 
 use crate::{
+    blobs::BlobStore,
+    config::Config,
+    encoding::{Encoded, Encoding},
+    handlers::send_to_devices,
     redis::Redis,
-    types::{Connections, Room, RoomMessage},
+    search::SearchIndex,
+    types::{
+        Connections, Device, DirectMessage, MentionEntry, Presences, Profile, Room, RoomLists,
+        RoomMessage, Typing, Upload,
+    },
 };
 
+const SESSION_TTL_SECS: i64 = 30 * 24 * 60 * 60;
+const DM_QUEUE_TTL_SECS: i64 = 30 * 24 * 60 * 60;
+pub const UPLOAD_TTL_SECS: i64 = 24 * 60 * 60;
+// The mentions inbox keeps the newest entries and is dropped after a quiet month.
+pub const MAX_MENTIONS: usize = 500;
+const MENTIONS_TTL_SECS: i64 = 30 * 24 * 60 * 60;
+
 #[derive(Clone)]
 pub struct AppState {
+    pub config: Config,
     pub redis: Redis,
     pub connections: Connections,
+    pub typing: Typing,
+    pub presence: Presences,
+    pub room_lists: RoomLists,
+    pub search: SearchIndex,
+    pub blobs: BlobStore,
+    pub image_decodes: Arc<Semaphore>,
+    // Room writes read the "rooms" list and write back by index, so they
+    // run one at a time, and never alongside a create or delete.
+    pub room_writes: Arc<Mutex<()>>,
 }
 
 impl AppState {
     pub fn new() -> Self {
+        let config = Config::from_env();
+        let search =
+            SearchIndex::open(&config.search_index_dir).expect("Error while opening search index");
+        let blobs = BlobStore::new(config.blob_dir.clone());
+        let image_decodes = Arc::new(Semaphore::new(config.image_decode_concurrency));
         Self {
+            config,
             redis: Redis::new(),
             connections: Arc::new(Mutex::new(HashMap::new())),
+            typing: Arc::new(Mutex::new(HashMap::new())),
+            presence: Arc::new(Mutex::new(HashMap::new())),
+            room_lists: Arc::new(Mutex::new(HashMap::new())),
+            search,
+            blobs,
+            room_writes: Arc::new(Mutex::new(())),
+            image_decodes,
         }
     }
     pub async fn _delete_users_rooms(&self, user: String) {
         let rooms = self.get_rooms().await;
         for room in rooms {
             if room.admin == user {
-                self.del_room(room.room).await;
+                self.del_room_if(&room.room, |r| r.admin == user).await;
             }
         }
     }
-    pub async fn add_to_room(&self, room: String, user: String) -> Room {
-        let mut r = self.get_room(room.clone()).await.clone();
-        let i = self.get_room_index(room).await;
+    pub async fn add_to_room(&self, room: String, user: String) -> Option<Room> {
+        let _writes = self.room_writes.lock().await;
+        let (i, mut r) = self.locate_room(&room).await?;
+        if r.users.contains(&user) {
+            return Some(r);
+        }
+        // Whoever reopens an empty room runs it.
+        if r.users.is_empty() {
+            r.admin = user.clone();
+        }
         r.users.push(user);
+        r.version += 1;
         self.redis.lset("rooms", i, &r.clone()).await.unwrap();
-        r
+        Some(r)
     }
+    // Empty rooms are left in place; the reaper decides when they go.
     pub async fn remove_from_room(&self, room: String, user: String) -> Option<Room> {
-        let mut r = self.get_room(room.clone()).await.clone();
-        let i = self.get_room_index(room.clone()).await;
+        let _writes = self.room_writes.lock().await;
+        let (i, mut r) = self.locate_room(&room).await?;
 
-        if r.users.len() == 1 && r.admin == user {
-            self.del_room(room).await;
-            return None;
-        }
-
-        let user_index = r.users.iter().position(|u| u.to_owned() == user);
+        let user_index = r.users.iter().position(|u| *u == user);
         match user_index {
             Some(index) => {
-                if r.admin == user {
-                    r.users.remove(index);
-                    r.admin = r.users.iter().next().unwrap().to_owned();
-                } else {
-                    r.users.remove(index);
+                r.users.remove(index);
+                if !r.former_users.contains(&user) {
+                    r.former_users.push(user.clone());
+                }
+                r.version += 1;
+                if r.admin == user && !r.users.is_empty() {
+                    r.admin = r.users.first().unwrap().to_owned();
+                    r.version += 1;
                 }
                 self.redis
                     .lset("rooms", i, &r.clone())
@@ -63,43 +113,178 @@ impl AppState {
             }
         }
 
-        return Some(r);
+        Some(r)
     }
-    pub async fn add_message(&self, room: String, message: RoomMessage) {
-        let mut r = self.get_room(room.clone()).await.clone();
-        let i = self.get_room_index(room).await;
-        r.messages.push(message);
+    pub async fn rename_room(&self, room: String, room_name: String) -> Option<Room> {
+        let _writes = self.room_writes.lock().await;
+        let (i, mut r) = self.locate_room(&room).await?;
+        r.room_name = room_name;
+        r.version += 1;
+        self.redis
+            .lset("rooms", i, &r)
+            .await
+            .expect("Error while setting room");
+        Some(r)
+    }
+    pub async fn add_message(&self, room: String, mut message: RoomMessage) -> Option<RoomMessage> {
+        let _writes = self.room_writes.lock().await;
+        let (i, mut r) = self.locate_room(&room).await?;
+        r.last_seq += 1;
+        message.seq = r.last_seq;
+        if let Some(root) = &message.thread_root
+            && let Some(root) = r.messages.iter_mut().find(|m| m.id == *root)
+        {
+            let summary = root.thread.get_or_insert_with(Default::default);
+            summary.reply_count += 1;
+            summary.last_reply_at = message.sent_at;
+        }
+        r.last_activity_at = message.sent_at;
+        r.messages.push(message.clone());
+        r.read_positions.insert(message.by.clone(), message.seq);
         self.redis.lset("rooms", i, &r).await.unwrap();
+        self.search.index_message(&r.room, &message);
+        Some(message)
     }
-    pub async fn get_room(&self, room: String) -> Room {
-        let rooms = self.get_rooms().await;
-        let i = rooms
-            .iter()
-            .position(|r| r.room == room)
-            .expect("Room index not found");
-        rooms.get(i).expect("Error room not found").clone()
+    // Drops the given messages and moves the start of the room's history up
+    // to `history_start`.
+    pub async fn prune_messages(
+        &self,
+        room: &str,
+        ids: &HashSet<&str>,
+        history_start: u64,
+    ) -> Option<Room> {
+        let _writes = self.room_writes.lock().await;
+        let (i, mut r) = self.locate_room(room).await?;
+        for message in r.messages.iter().filter(|m| ids.contains(m.id.as_str())) {
+            self.search.remove_message(&message.id);
+        }
+        r.messages.retain(|m| !ids.contains(m.id.as_str()));
+        r.history_start = r.history_start.max(history_start);
+        self.redis
+            .lset("rooms", i, &r)
+            .await
+            .expect("Error while setting room");
+        Some(r)
     }
-    pub async fn get_room_index(&self, room: String) -> usize {
-        let rooms = self.get_rooms().await;
-        rooms
-            .iter()
-            .position(|r| r.room == room)
-            .expect("error room not found")
+    pub async fn mark_read(&self, room: String, user: String, up_to_seq: u64) -> Option<Room> {
+        let _writes = self.room_writes.lock().await;
+        let (i, mut r) = self.locate_room(&room).await?;
+        let up_to_seq = up_to_seq.min(r.last_seq);
+        let position = r.read_positions.entry(user).or_default();
+        if *position >= up_to_seq {
+            return None;
+        }
+        *position = up_to_seq;
+        self.redis
+            .lset("rooms", i, &r)
+            .await
+            .expect("Error while setting room");
+        Some(r)
+    }
+    pub async fn set_read_receipts(&self, room: String, enabled: bool) {
+        let _writes = self.room_writes.lock().await;
+        let Some((i, mut r)) = self.locate_room(&room).await else {
+            return;
+        };
+        r.read_receipts = enabled;
+        self.redis
+            .lset("rooms", i, &r)
+            .await
+            .expect("Error while setting room");
+    }
+    pub async fn update_room<F>(&self, room: &str, update: F) -> Option<Room>
+    where
+        F: FnOnce(&mut Room),
+    {
+        let _writes = self.room_writes.lock().await;
+        let (i, mut r) = self.locate_room(room).await?;
+        update(&mut r);
+        r.version += 1;
+        self.redis
+            .lset("rooms", i, &r)
+            .await
+            .expect("Error while setting room");
+        Some(r)
+    }
+    pub async fn update_message<F>(
+        &self,
+        room: String,
+        message_id: &str,
+        update: F,
+    ) -> Option<RoomMessage>
+    where
+        F: FnOnce(&mut RoomMessage),
+    {
+        let _writes = self.room_writes.lock().await;
+        let (i, mut r) = self.locate_room(&room).await?;
+        let message = r.messages.iter_mut().find(|m| m.id == message_id)?;
+        update(message);
+        let updated = message.clone();
+        self.redis
+            .lset("rooms", i, &r)
+            .await
+            .expect("Error while setting room");
+        self.search.index_message(&room, &updated);
+        Some(updated)
+    }
+    pub async fn find_room(&self, room: &str) -> Option<Room> {
+        self.get_rooms().await.into_iter().find(|r| r.room == room)
+    }
+    // The room and its index in the "rooms" list. Indices shift when rooms
+    // are created or deleted, so only use them under `room_writes`.
+    async fn locate_room(&self, room: &str) -> Option<(usize, Room)> {
+        self.get_rooms()
+            .await
+            .into_iter()
+            .enumerate()
+            .find(|(_, r)| r.room == room)
     }
     pub async fn _get_connections(&self) -> Vec<String> {
         let connection_guard = self.connections.lock().await;
         let mut vec = Vec::new();
         for conn in connection_guard.iter() {
-            let (user, _itx) = conn;
+            let (user, _devices) = conn;
             vec.push(user.clone());
         }
         vec
     }
-    pub async fn remove_from_rooms(&self, user: String) {
-        let rooms = self.get_rooms().await;
-        for room in rooms.iter() {
-            if room.users.iter().find(|u| **u == user.clone()).is_some() {
-                self.remove_from_room(room.room.clone(), user.clone()).await;
+    pub async fn add_connection(
+        &self,
+        user: &str,
+        connection: &str,
+        tx: UnboundedSender<Message>,
+        encoding: Encoding,
+    ) -> bool {
+        let mut connection_guard = self.connections.lock().await;
+        let devices = connection_guard.entry(user.to_owned()).or_default();
+        devices.insert(connection.to_owned(), Device { tx, encoding });
+        devices.len() == 1
+    }
+    pub async fn remove_connection(&self, user: &str, connection: &str) -> bool {
+        let mut connection_guard = self.connections.lock().await;
+        let Some(devices) = connection_guard.get_mut(user) else {
+            return true;
+        };
+        devices.remove(connection);
+        if devices.is_empty() {
+            connection_guard.remove(user);
+            return true;
+        }
+        false
+    }
+    pub async fn send_to_user(&self, user: &str, message: Message) -> bool {
+        let connection_guard = self.connections.lock().await;
+        send_to_devices(connection_guard.get(user), &Encoded::new(message))
+    }
+    // Sends to the user's devices other than `connection`.
+    pub async fn send_to_other_devices(&self, user: &str, connection: &str, message: Message) {
+        let message = Encoded::new(message);
+        let connection_guard = self.connections.lock().await;
+        for (id, device) in connection_guard.get(user).into_iter().flatten() {
+            if id != connection
+                && let Err(e) = device.tx.send(message.get(device.encoding))
+            {
+                error!("Error while sending message to connection {id}: {e:?}");
             }
         }
     }
@@ -108,14 +293,211 @@ impl AppState {
         res
     }
     pub async fn create_room(&self, room: Room) {
+        let _writes = self.room_writes.lock().await;
         self.redis.lpush("rooms", &room).await.unwrap();
     }
-    pub async fn del_room(&self, room: String) {
-        let rooms = self.get_rooms().await;
-        let index = rooms.iter().position(|r| r.room == room);
-        match index {
-            Some(i) => self.redis.lset_delete("rooms", i).await.unwrap(),
-            None => error!("room not found"),
+    // Deletes the room if `delete` still agrees once writes are held off,
+    // returning what was deleted.
+    pub async fn del_room_if<F>(&self, room: &str, delete: F) -> Option<Room>
+    where
+        F: FnOnce(&Room) -> bool,
+    {
+        let _writes = self.room_writes.lock().await;
+        let (i, r) = self.locate_room(room).await?;
+        if !delete(&r) {
+            return None;
+        }
+        self.redis.lset_delete("rooms", i).await.unwrap();
+        self.search.remove_room(room);
+        Some(r)
+    }
+    // Indexes every stored message when the search index starts out empty,
+    // e.g. on first run or after the index directory was removed.
+    pub async fn backfill_search_index(&self) {
+        if !self.search.is_empty() {
+            return;
+        }
+        for room in self.get_rooms().await {
+            for message in &room.messages {
+                self.search.index_message(&room.room, message);
+            }
+        }
+        self.search.commit();
+    }
+    // Resuming a session extends its expiry.
+    pub async fn resume_session(&self, session: &str) -> Option<String> {
+        let key = format!("session:{}", session);
+        let user = self.redis.get(&key).await.ok()?;
+        let _ = self.redis.expire(&key, SESSION_TTL_SECS).await;
+        Some(user)
+    }
+    pub async fn create_session(&self, user: &str) -> String {
+        let session = Uuid::new_v4().to_string();
+        let key = format!("session:{}", session);
+        self.redis
+            .set(&key, &user)
+            .await
+            .expect("Error while creating session");
+        let _ = self.redis.expire(&key, SESSION_TTL_SECS).await;
+        session
+    }
+    pub async fn add_direct_message(&self, message: &DirectMessage) {
+        let key = DirectMessage::conversation_key(&message.from, &message.to);
+        if self.redis.llen(&key).await.unwrap_or(0) == 0 {
+            self.add_conversation(&message.from, &message.to).await;
+            self.add_conversation(&message.to, &message.from).await;
+        }
+        self.redis.rpush(&key, message).await.unwrap();
+    }
+    async fn add_conversation(&self, user: &str, with: &str) {
+        let mut conversations = self.get_conversations(user).await;
+        if !conversations.iter().any(|c| c == with) {
+            conversations.push(with.to_owned());
+            self.redis
+                .set(&format!("dm_conversations:{}", user), &conversations)
+                .await
+                .unwrap();
+        }
+    }
+    pub async fn get_conversations(&self, user: &str) -> Vec<String> {
+        self.redis
+            .get(&format!("dm_conversations:{}", user))
+            .await
+            .unwrap_or_default()
+    }
+    pub async fn direct_message_count(&self, a: &str, b: &str) -> usize {
+        self.redis
+            .llen(&DirectMessage::conversation_key(a, b))
+            .await
+            .unwrap_or(0)
+    }
+    pub async fn get_direct_messages(
+        &self,
+        a: &str,
+        b: &str,
+        start: usize,
+        stop: usize,
+    ) -> Vec<DirectMessage> {
+        self.redis
+            .lrange(
+                &DirectMessage::conversation_key(a, b),
+                start as isize,
+                stop as isize,
+            )
+            .await
+            .unwrap_or_default()
+    }
+    // Queues expire a while after the last message, for users who never
+    // come back.
+    pub async fn queue_direct_message(&self, message: &DirectMessage) {
+        let key = format!("dm_queue:{}", message.to);
+        self.redis.rpush(&key, message).await.unwrap();
+        let _ = self.redis.expire(&key, DM_QUEUE_TTL_SECS).await;
+    }
+    pub async fn take_queued_direct_messages(&self, user: &str) -> Vec<DirectMessage> {
+        self.redis
+            .take_all(&format!("dm_queue:{}", user))
+            .await
+            .unwrap_or_default()
+    }
+    pub async fn add_mention(&self, user: &str, mention: &MentionEntry) {
+        self.redis
+            .lpush_capped(
+                &format!("mentions:{}", user),
+                mention,
+                MAX_MENTIONS,
+                MENTIONS_TTL_SECS,
+            )
+            .await
+            .unwrap();
+    }
+    pub async fn get_mentions(&self, user: &str, start: usize, stop: usize) -> Vec<MentionEntry> {
+        self.redis
+            .lrange(&format!("mentions:{}", user), start as isize, stop as isize)
+            .await
+            .unwrap_or_default()
+    }
+    pub async fn get_profile(&self, user: &str) -> Profile {
+        self.redis
+            .get(&format!("profile:{}", user))
+            .await
+            .unwrap_or_else(|_| Profile::empty(user))
+    }
+    pub async fn set_profile(&self, profile: &Profile) {
+        self.redis
+            .set(&format!("profile:{}", profile.user_id), profile)
+            .await
+            .expect("Error while setting profile");
+    }
+    pub async fn get_profiles(&self, users: &[String]) -> HashMap<String, Profile> {
+        let mut profiles = HashMap::new();
+        for user in users {
+            if !profiles.contains_key(user) {
+                profiles.insert(user.clone(), self.get_profile(user).await);
+            }
+        }
+        profiles
+    }
+    pub async fn room_peers(&self, user: &str) -> Vec<String> {
+        let peers: HashSet<String> = self
+            .get_rooms()
+            .await
+            .into_iter()
+            .filter(|r| r.users.iter().any(|u| u == user))
+            .flat_map(|r| r.users)
+            .filter(|u| u != user)
+            .collect();
+        peers.into_iter().collect()
+    }
+    pub async fn get_upload(&self, id: &str) -> Option<Upload> {
+        self.redis.get(&format!("upload:{}", id)).await.ok()
+    }
+    // Records expire, so uploads that are never finished or cancelled don't
+    // linger; `uploads:<user>` tracks which ones a user has open.
+    pub async fn set_upload(&self, upload: &Upload) {
+        let key = format!("upload:{}", upload.id);
+        self.redis
+            .set(&key, upload)
+            .await
+            .expect("Error while saving upload");
+        let _ = self.redis.expire(&key, UPLOAD_TTL_SECS).await;
+        let open = format!("uploads:{}", upload.user);
+        let _ = self.redis.sadd(&open, &upload.id).await;
+        let _ = self.redis.expire(&open, UPLOAD_TTL_SECS).await;
+    }
+    pub async fn del_upload(&self, upload: &Upload) {
+        if let Err(e) = self.redis.del(&format!("upload:{}", upload.id)).await {
+            error!("Error while removing upload {}: {:?}", upload.id, e);
+        }
+        let _ = self
+            .redis
+            .srem(&format!("uploads:{}", upload.user), &upload.id)
+            .await;
+    }
+    // Uploads the user has started and not finished, forgetting expired ones.
+    pub async fn open_uploads(&self, user: &str) -> Vec<Upload> {
+        let key = format!("uploads:{}", user);
+        let mut uploads = Vec::new();
+        for id in self.redis.smembers(&key).await.unwrap_or_default() {
+            match self.get_upload(&id).await {
+                Some(upload) => uploads.push(upload),
+                None => {
+                    let _ = self.redis.srem(&key, &id).await;
+                }
+            }
+        }
+        uploads
+    }
+    pub async fn get_last_seen(&self, user: &str) -> Option<u64> {
+        self.redis.get(&format!("last_seen:{}", user)).await.ok()
+    }
+    pub async fn set_last_seen(&self, user: &str, last_seen: u64) {
+        if let Err(e) = self
+            .redis
+            .set(&format!("last_seen:{}", user), &last_seen)
+            .await
+        {
+            error!("Error while saving last seen for {}: {:?}", user, e);
         }
     }
 }
@@ -0,0 +1,134 @@
+use std::{io, path::PathBuf, time::Duration};
+
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncWriteExt},
+};
+
+// Content-addressed file storage. Finished blobs live under
+// `<dir>/<first two hex chars>/<sha256>`, partial uploads under
+// `<dir>/uploads/<upload id>`.
+#[derive(Clone)]
+pub struct BlobStore {
+    dir: PathBuf,
+}
+
+impl BlobStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    pub fn is_blob_id(id: &str) -> bool {
+        id.len() == 64
+            && id
+                .bytes()
+                .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    }
+
+    pub fn blob_path(&self, id: &str) -> PathBuf {
+        self.dir.join(&id[..2]).join(id)
+    }
+
+    fn upload_path(&self, upload_id: &str) -> PathBuf {
+        self.dir.join("uploads").join(upload_id)
+    }
+
+    // Bytes received so far for an upload.
+    pub async fn received(&self, upload_id: &str) -> u64 {
+        fs::metadata(self.upload_path(upload_id))
+            .await
+            .map_or(0, |meta| meta.len())
+    }
+
+    pub async fn append(&self, upload_id: &str, data: &[u8]) -> io::Result<u64> {
+        let path = self.upload_path(upload_id);
+        fs::create_dir_all(self.dir.join("uploads")).await?;
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        file.write_all(data).await?;
+        file.flush().await?;
+        Ok(file.metadata().await?.len())
+    }
+
+    // Ids of partial uploads that haven't been written to for `idle`.
+    pub async fn idle_uploads(&self, idle: Duration) -> io::Result<Vec<String>> {
+        let mut ids = Vec::new();
+        let mut entries = match fs::read_dir(self.dir.join("uploads")).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(ids),
+            Err(e) => return Err(e),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let modified = entry.metadata().await?.modified()?;
+            if modified.elapsed().is_ok_and(|elapsed| elapsed >= idle)
+                && let Some(id) = entry.file_name().to_str()
+            {
+                ids.push(id.to_owned());
+            }
+        }
+        Ok(ids)
+    }
+
+    pub async fn discard(&self, upload_id: &str) {
+        let _ = fs::remove_file(self.upload_path(upload_id)).await;
+    }
+
+    // Hashes a finished upload and moves it into place. Returns the blob id,
+    // or None when the content doesn't match the expected checksum. Content
+    // that's already stored is deduplicated here rather than skipped at
+    // `start_upload`, so uploaders always have to send the bytes.
+    pub async fn finish(&self, upload_id: &str, sha256: &str) -> io::Result<Option<String>> {
+        let path = self.upload_path(upload_id);
+        let mut file = fs::File::open(&path).await?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let read = file.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+        let blob_id = format!("{:x}", hasher.finalize());
+        if blob_id != sha256 {
+            self.discard(upload_id).await;
+            return Ok(None);
+        }
+
+        let target = self.blob_path(&blob_id);
+        if fs::try_exists(&target).await? {
+            self.discard(upload_id).await;
+        } else {
+            fs::create_dir_all(self.dir.join(&blob_id[..2])).await?;
+            fs::rename(&path, &target).await?;
+        }
+        Ok(Some(blob_id))
+    }
+
+    // Reads a finished upload into memory and drops it, for content that's
+    // transformed before it's stored. None when the checksum doesn't match.
+    pub async fn take_upload(&self, upload_id: &str, sha256: &str) -> io::Result<Option<Vec<u8>>> {
+        let data = fs::read(self.upload_path(upload_id)).await;
+        self.discard(upload_id).await;
+        let data = data?;
+        Ok((format!("{:x}", Sha256::digest(&data)) == sha256).then_some(data))
+    }
+
+    // Stores content produced by the server itself, e.g. stripped images and
+    // thumbnails.
+    pub async fn store(&self, data: &[u8]) -> io::Result<String> {
+        let blob_id = format!("{:x}", Sha256::digest(data));
+        let target = self.blob_path(&blob_id);
+        if !fs::try_exists(&target).await? {
+            let upload_id = uuid::Uuid::new_v4().to_string();
+            self.append(&upload_id, data).await?;
+            fs::create_dir_all(self.dir.join(&blob_id[..2])).await?;
+            fs::rename(self.upload_path(&upload_id), &target).await?;
+        }
+        Ok(blob_id)
+    }
+}
@@ -0,0 +1,505 @@
+use std::{
+    io::{self, Write},
+    pin::Pin,
+    sync::{Arc, OnceLock},
+    task::{Context, Poll},
+};
+
+use flate2::{Compression, Decompress, FlushDecompress, write::DeflateEncoder};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::{
+    Message,
+    protocol::frame::{
+        Frame,
+        coding::{Data, OpCode},
+    },
+};
+
+use crate::config::DeflateConfig;
+
+// Trailer every sync flush ends with; RFC 7692 strips it from the wire.
+const SYNC_TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+// Inflated output is produced and size-checked this much at a time.
+const INFLATE_CHUNK: usize = 32 * 1024;
+
+// permessage-deflate parameters agreed on during the handshake.
+#[derive(Clone, Copy, Debug)]
+pub struct Deflate {
+    pub server_no_context_takeover: bool,
+    pub client_no_context_takeover: bool,
+}
+
+impl Deflate {
+    // Picks the first permessage-deflate offer from a Sec-WebSocket-Extensions
+    // header that we can honour. We always use a 15 bit window, so offers that
+    // restrict the server window are declined.
+    pub fn negotiate(config: &DeflateConfig, offered: &str) -> Option<Self> {
+        offered.split(',').find_map(|offer| {
+            let mut params = offer.split(';').map(str::trim);
+            if params.next()? != "permessage-deflate" {
+                return None;
+            }
+            let mut deflate = Self {
+                server_no_context_takeover: config.server_no_context_takeover,
+                client_no_context_takeover: config.client_no_context_takeover,
+            };
+            for param in params {
+                let (name, value) = match param.split_once('=') {
+                    Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                    None => (param, None),
+                };
+                match (name, value) {
+                    ("server_no_context_takeover", None) => {
+                        deflate.server_no_context_takeover = true
+                    }
+                    ("client_no_context_takeover", None) => {}
+                    ("server_max_window_bits", Some("15")) => {}
+                    ("client_max_window_bits", None | Some(_)) => {}
+                    _ => return None,
+                }
+            }
+            Some(deflate)
+        })
+    }
+
+    pub fn response(&self) -> String {
+        let mut response = String::from("permessage-deflate");
+        if self.server_no_context_takeover {
+            response.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            response.push_str("; client_no_context_takeover");
+        }
+        response
+    }
+}
+
+// Compresses outgoing data messages above the configured threshold into
+// frames with RSV1 set. Smaller messages and control frames pass through.
+pub struct Compressor {
+    deflate: Deflate,
+    threshold: usize,
+    encoder: DeflateEncoder<Vec<u8>>,
+}
+
+impl Compressor {
+    pub fn new(deflate: Deflate, config: &DeflateConfig) -> Self {
+        Self {
+            deflate,
+            threshold: config.threshold,
+            encoder: DeflateEncoder::new(Vec::new(), Compression::new(config.level)),
+        }
+    }
+
+    pub fn compress(&mut self, message: Message) -> Message {
+        let (data, opcode) = match &message {
+            Message::Text(text) => (text.as_bytes(), Data::Text),
+            Message::Binary(data) => (&data[..], Data::Binary),
+            _ => return message,
+        };
+        if data.len() < self.threshold {
+            return message;
+        }
+        if self
+            .encoder
+            .write_all(data)
+            .and_then(|_| self.encoder.flush())
+            .is_err()
+        {
+            return message;
+        }
+        let mut payload = std::mem::take(self.encoder.get_mut());
+        if payload.ends_with(&SYNC_TRAILER) {
+            payload.truncate(payload.len() - SYNC_TRAILER.len());
+        }
+        if self.deflate.server_no_context_takeover {
+            let _ = self.encoder.reset(Vec::new());
+        }
+        let mut frame = Frame::message(payload, OpCode::Data(opcode), true);
+        frame.header_mut().rsv1 = true;
+        Message::Frame(frame)
+    }
+}
+
+// tungstenite rejects frames with RSV1 set, so compressed client messages are
+// inflated here, below the WebSocket layer, and handed on as plain frames.
+// Until the handshake settles on permessage-deflate bytes pass through as is.
+// `max_size` is the frame and message limit tungstenite is configured with;
+// it's enforced here too, on frames, compressed messages and inflated output.
+pub struct DeflateStream<S> {
+    inner: S,
+    negotiated: Arc<OnceLock<Deflate>>,
+    max_size: usize,
+    decompress: Decompress,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    message: Option<(u8, Vec<u8>)>,
+}
+
+fn too_large(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl<S> DeflateStream<S> {
+    pub fn new(inner: S, negotiated: Arc<OnceLock<Deflate>>, max_size: usize) -> Self {
+        Self {
+            inner,
+            negotiated,
+            max_size,
+            decompress: Decompress::new(false),
+            input: Vec::new(),
+            output: Vec::new(),
+            message: None,
+        }
+    }
+
+    fn inflate(&mut self, deflate: Deflate, opcode: u8, mut payload: Vec<u8>) -> io::Result<()> {
+        payload.extend_from_slice(&SYNC_TRAILER);
+        let mut input = &payload[..];
+        let mut chunk = vec![0; INFLATE_CHUNK];
+        let mut inflated = Vec::new();
+        loop {
+            let (total_in, total_out) = (self.decompress.total_in(), self.decompress.total_out());
+            self.decompress
+                .decompress(input, &mut chunk, FlushDecompress::Sync)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let consumed = (self.decompress.total_in() - total_in) as usize;
+            let produced = (self.decompress.total_out() - total_out) as usize;
+            input = &input[consumed..];
+            inflated.extend_from_slice(&chunk[..produced]);
+            // Checked as it grows, so a compression bomb is cut off one chunk
+            // past the limit instead of being inflated in full.
+            if inflated.len() > self.max_size {
+                return Err(too_large("inflated message too large"));
+            }
+            if (input.is_empty() && produced < chunk.len()) || (consumed == 0 && produced == 0) {
+                break;
+            }
+        }
+        if deflate.client_no_context_takeover {
+            self.decompress.reset(false);
+        }
+
+        // Client frames must be masked; an all-zero key leaves the payload as is.
+        self.output.push(0x80 | opcode);
+        match inflated.len() {
+            len if len < 126 => self.output.push(0x80 | len as u8),
+            len if len <= u16::MAX as usize => {
+                self.output.push(0x80 | 126);
+                self.output.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                self.output.push(0x80 | 127);
+                self.output.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        self.output.extend_from_slice(&[0; 4]);
+        self.output.extend_from_slice(&inflated);
+        Ok(())
+    }
+
+    // Moves every complete frame from `input` to `output`, inflating
+    // compressed messages and passing everything else through unchanged.
+    fn process(&mut self, deflate: Deflate) -> io::Result<()> {
+        loop {
+            let Some((header_len, payload_len)) = frame_len(&self.input) else {
+                return Ok(());
+            };
+            // Rejected before any of the payload is buffered.
+            let frame_len = usize::try_from(payload_len)
+                .ok()
+                .filter(|len| *len <= self.max_size)
+                .and_then(|len| len.checked_add(header_len))
+                .ok_or_else(|| too_large("frame too large"))?;
+            if self.input.len() < frame_len {
+                return Ok(());
+            }
+            let frame: Vec<u8> = self.input.drain(..frame_len).collect();
+            let fin = frame[0] & 0x80 != 0;
+            let rsv1 = frame[0] & 0x40 != 0;
+            let opcode = frame[0] & 0x0f;
+
+            let compressed = match opcode {
+                0x1 | 0x2 if rsv1 => {
+                    self.message = Some((opcode, Vec::new()));
+                    true
+                }
+                0x0 => self.message.is_some(),
+                _ => false,
+            };
+            if !compressed {
+                self.output.extend_from_slice(&frame);
+                continue;
+            }
+
+            let mut payload = frame[header_len..].to_vec();
+            if frame[1] & 0x80 != 0 {
+                let mask = &frame[header_len - 4..header_len];
+                for (i, byte) in payload.iter_mut().enumerate() {
+                    *byte ^= mask[i % 4];
+                }
+            }
+            let Some((_, data)) = self.message.as_mut() else {
+                continue;
+            };
+            if data.len() + payload.len() > self.max_size {
+                return Err(too_large("compressed message too large"));
+            }
+            data.extend_from_slice(&payload);
+            if fin && let Some((opcode, data)) = self.message.take() {
+                self.inflate(deflate, opcode, data)?;
+            }
+        }
+    }
+}
+
+// Header length and the declared payload length of the frame at the start of
+// `data`, once enough of the header has arrived to tell.
+fn frame_len(data: &[u8]) -> Option<(usize, u64)> {
+    let first = *data.get(1)?;
+    let mask_len = if first & 0x80 != 0 { 4 } else { 0 };
+    let (len_bytes, payload_len) = match first & 0x7f {
+        126 => (
+            2,
+            u16::from_be_bytes(data.get(2..4)?.try_into().ok()?) as u64,
+        ),
+        127 => (8, u64::from_be_bytes(data.get(2..10)?.try_into().ok()?)),
+        len => (0, len as u64),
+    };
+    Some((2 + len_bytes + mask_len, payload_len))
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for DeflateStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.output.is_empty() {
+                let n = this.output.len().min(buf.remaining());
+                buf.put_slice(&this.output[..n]);
+                this.output.drain(..n);
+                return Poll::Ready(Ok(()));
+            }
+            let Some(deflate) = this.negotiated.get().copied() else {
+                return Pin::new(&mut this.inner).poll_read(cx, buf);
+            };
+
+            let mut chunk = [0; 8192];
+            let mut chunk_buf = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf) {
+                Poll::Ready(Ok(())) if chunk_buf.filled().is_empty() => {
+                    return Poll::Ready(Ok(()));
+                }
+                Poll::Ready(Ok(())) => {
+                    this.input.extend_from_slice(chunk_buf.filled());
+                    this.process(deflate)?;
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for DeflateStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_SIZE: usize = 64 * 1024;
+
+    fn config(no_context_takeover: bool) -> DeflateConfig {
+        DeflateConfig {
+            enabled: true,
+            level: 6,
+            threshold: 0,
+            server_no_context_takeover: no_context_takeover,
+            client_no_context_takeover: no_context_takeover,
+        }
+    }
+
+    fn deflate(no_context_takeover: bool) -> Deflate {
+        Deflate {
+            server_no_context_takeover: no_context_takeover,
+            client_no_context_takeover: no_context_takeover,
+        }
+    }
+
+    // What a client would send: the compressed payload of `text`.
+    fn compress(compressor: &mut Compressor, text: &str) -> Vec<u8> {
+        match compressor.compress(Message::text(text)) {
+            Message::Frame(frame) => {
+                assert!(frame.header().rsv1);
+                frame.into_payload().to_vec()
+            }
+            other => panic!("expected a compressed frame, got {:?}", other),
+        }
+    }
+
+    fn client_frame(fin: bool, rsv1: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![(fin as u8) << 7 | (rsv1 as u8) << 6 | opcode];
+        match payload.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    // Splits `output` back into (opcode, unmasked payload) pairs.
+    fn frames(output: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut frames = Vec::new();
+        let mut rest = output;
+        while let Some((header_len, payload_len)) = frame_len(rest) {
+            let end = header_len + payload_len as usize;
+            let mask = &rest[header_len - 4..header_len];
+            let payload = rest[header_len..end]
+                .iter()
+                .enumerate()
+                .map(|(i, b)| b ^ mask[i % 4])
+                .collect();
+            frames.push((rest[0] & 0x0f, payload));
+            rest = &rest[end..];
+        }
+        assert!(rest.is_empty());
+        frames
+    }
+
+    fn stream_of(input: &[u8]) -> DeflateStream<()> {
+        let mut stream = DeflateStream::new((), Arc::new(OnceLock::new()), MAX_SIZE);
+        stream.input.extend_from_slice(input);
+        stream
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut compressor = Compressor::new(deflate(false), &config(false));
+        let text = "hello hello hello hello";
+        let mut stream = stream_of(&client_frame(
+            true,
+            true,
+            0x1,
+            &compress(&mut compressor, text),
+        ));
+        stream.process(deflate(false)).unwrap();
+        assert_eq!(
+            frames(&stream.output),
+            vec![(0x1, text.as_bytes().to_vec())]
+        );
+    }
+
+    #[test]
+    fn uncompressed_frames_pass_through() {
+        let frame = client_frame(true, false, 0x2, b"plain");
+        let mut stream = stream_of(&frame);
+        stream.process(deflate(false)).unwrap();
+        assert_eq!(stream.output, frame);
+    }
+
+    #[test]
+    fn fragmented_message() {
+        let mut compressor = Compressor::new(deflate(false), &config(false));
+        let text = "a fragmented message, a fragmented message";
+        let payload = compress(&mut compressor, text);
+        let (first, second) = payload.split_at(payload.len() / 2);
+        let mut input = client_frame(false, true, 0x1, first);
+        input.extend(client_frame(true, false, 0x9, b"ping"));
+        input.extend(client_frame(true, false, 0x0, second));
+
+        // Delivered a byte at a time, as a slow socket might.
+        let mut stream = stream_of(&[]);
+        for byte in input {
+            stream.input.push(byte);
+            stream.process(deflate(false)).unwrap();
+        }
+        assert_eq!(
+            frames(&stream.output),
+            vec![(0x9, b"ping".to_vec()), (0x1, text.as_bytes().to_vec())]
+        );
+    }
+
+    #[test]
+    fn context_takeover() {
+        for no_context_takeover in [false, true] {
+            let mut compressor =
+                Compressor::new(deflate(no_context_takeover), &config(no_context_takeover));
+            let text = "the same text twice, the same text twice";
+            let first = compress(&mut compressor, text);
+            let second = compress(&mut compressor, text);
+            // With a shared window the repeat is a back-reference into the
+            // first message, so it only inflates with the context kept.
+            assert_eq!(no_context_takeover, first == second);
+
+            let mut input = client_frame(true, true, 0x1, &first);
+            input.extend(client_frame(true, true, 0x1, &second));
+            let mut stream = stream_of(&input);
+            stream.process(deflate(no_context_takeover)).unwrap();
+            let expected = (0x1, text.as_bytes().to_vec());
+            assert_eq!(frames(&stream.output), vec![expected.clone(), expected]);
+        }
+    }
+
+    #[test]
+    fn oversized_frame_is_rejected_from_its_header() {
+        let mut header = vec![0x82, 0x80 | 127];
+        header.extend_from_slice(&(MAX_SIZE as u64 + 1).to_be_bytes());
+        let mut stream = stream_of(&header);
+        assert!(stream.process(deflate(false)).is_err());
+
+        let mut header = vec![0x82, 0x80 | 127];
+        header.extend_from_slice(&u64::MAX.to_be_bytes());
+        let mut stream = stream_of(&header);
+        assert!(stream.process(deflate(false)).is_err());
+    }
+
+    #[test]
+    fn compression_bomb_is_rejected() {
+        let mut compressor = Compressor::new(deflate(false), &config(false));
+        let bomb = compress(&mut compressor, &"0".repeat(MAX_SIZE * 16));
+        assert!(bomb.len() < MAX_SIZE);
+        let mut stream = stream_of(&client_frame(true, true, 0x1, &bomb));
+        assert!(stream.process(deflate(false)).is_err());
+        assert!(stream.output.is_empty());
+    }
+
+    #[test]
+    fn frame_len_waits_for_the_header() {
+        assert_eq!(frame_len(&[0x81]), None);
+        assert_eq!(frame_len(&[0x81, 0x85]), Some((6, 5)));
+        assert_eq!(frame_len(&[0x81, 0xfe, 0x01]), None);
+        assert_eq!(frame_len(&[0x81, 0xfe, 0x01, 0x00]), Some((8, 256)));
+        assert_eq!(
+            frame_len(&[0x81, 0x7f, 0, 0, 0, 0, 0, 1, 0, 0]),
+            Some((10, 65536))
+        );
+    }
+}
@@ -0,0 +1,107 @@
+use std::{env, path::PathBuf, str::FromStr, time::Duration};
+
+use crate::types::MessageRetention;
+
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    env::var(key)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+#[derive(Clone, Debug)]
+pub struct DeflateConfig {
+    pub enabled: bool,
+    pub level: u32,
+    pub threshold: usize,
+    pub server_no_context_takeover: bool,
+    pub client_no_context_takeover: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub ping_interval: Duration,
+    pub idle_timeout: Duration,
+    pub max_protocol_violations: usize,
+    pub max_message_size: usize,
+    // Largest WebSocket frame or message accepted, compressed or inflated.
+    pub max_frame_size: usize,
+    pub max_room_size: usize,
+    pub ice_servers: serde_json::Value,
+    pub deflate: DeflateConfig,
+    pub room_grace_period: Duration,
+    pub room_reaper_interval: Duration,
+    // Zero keeps archived rooms forever.
+    pub archive_retention: Duration,
+    pub message_retention: MessageRetention,
+    pub retention_interval: Duration,
+    // Pruned messages are appended here before they're dropped, if set.
+    pub cold_storage_dir: Option<PathBuf>,
+    pub search_index_dir: PathBuf,
+    pub blob_dir: PathBuf,
+    pub http_addr: String,
+    pub max_upload_size: u64,
+    pub upload_chunk_size: usize,
+    pub allowed_mime_types: Vec<String>,
+    // Longest side of generated image thumbnails, in pixels.
+    pub thumbnail_size: u32,
+    // Images decoded at once; each decode may allocate up to 128 MiB.
+    pub image_decode_concurrency: usize,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Self {
+            // `tokio::time::interval` panics on a zero period.
+            ping_interval: Duration::from_secs(env_or("PING_INTERVAL_SECS", 20).max(1)),
+            idle_timeout: Duration::from_secs(env_or("IDLE_TIMEOUT_SECS", 60)),
+            max_protocol_violations: env_or("MAX_PROTOCOL_VIOLATIONS", 5),
+            max_message_size: env_or("MAX_MESSAGE_SIZE", 4000),
+            // Upload chunks have to fit, header included.
+            max_frame_size: env_or("WS_MAX_FRAME_SIZE", 1024 * 1024)
+                .max(env_or("UPLOAD_CHUNK_SIZE", 256 * 1024) + 1024),
+            max_room_size: env_or("MAX_ROOM_SIZE", 100),
+            ice_servers: env::var("ICE_SERVERS")
+                .ok()
+                .and_then(|servers| serde_json::from_str(&servers).ok())
+                .unwrap_or_else(
+                    || serde_json::json!([{ "urls": ["stun:stun.l.google.com:19302"] }]),
+                ),
+            deflate: DeflateConfig {
+                enabled: env_or("WS_DEFLATE", false),
+                level: env_or("WS_DEFLATE_LEVEL", 6).min(9),
+                threshold: env_or("WS_DEFLATE_THRESHOLD", 1024),
+                server_no_context_takeover: env_or("WS_DEFLATE_SERVER_NO_CONTEXT_TAKEOVER", false),
+                client_no_context_takeover: env_or("WS_DEFLATE_CLIENT_NO_CONTEXT_TAKEOVER", false),
+            },
+            room_grace_period: Duration::from_secs(env_or("ROOM_GRACE_PERIOD_SECS", 300)),
+            room_reaper_interval: Duration::from_secs(env_or("ROOM_REAPER_INTERVAL_SECS", 60)),
+            archive_retention: Duration::from_secs(
+                env_or("ARCHIVE_RETENTION_DAYS", 90) * 24 * 60 * 60,
+            ),
+            message_retention: MessageRetention {
+                max_count: Some(env_or("MESSAGE_RETENTION_MAX_COUNT", 0))
+                    .filter(|count| *count > 0),
+                max_age_hours: Some(env_or("MESSAGE_RETENTION_MAX_AGE_HOURS", 0))
+                    .filter(|hours| *hours > 0),
+            },
+            retention_interval: Duration::from_secs(env_or("RETENTION_INTERVAL_SECS", 300)),
+            cold_storage_dir: env::var("COLD_STORAGE_DIR").ok().map(PathBuf::from),
+            search_index_dir: env_or("SEARCH_INDEX_DIR", PathBuf::from("data/search")),
+            blob_dir: env_or("BLOB_DIR", PathBuf::from("data/blobs")),
+            http_addr: env_or("HTTP_ADDR", "127.0.0.1:4001".to_owned()),
+            max_upload_size: env_or("MAX_UPLOAD_SIZE", 25 * 1024 * 1024),
+            upload_chunk_size: env_or("UPLOAD_CHUNK_SIZE", 256 * 1024),
+            allowed_mime_types: env_or(
+                "ALLOWED_MIME_TYPES",
+                "image/png,image/jpeg,image/gif,image/webp,application/pdf,text/plain".to_owned(),
+            )
+            .split(',')
+            .map(|mime| mime.trim().to_lowercase())
+            .filter(|mime| !mime.is_empty())
+            .collect(),
+            thumbnail_size: env_or("THUMBNAIL_SIZE", 320).max(1),
+            image_decode_concurrency: env_or("IMAGE_DECODE_CONCURRENCY", 2).max(1),
+        }
+    }
+}
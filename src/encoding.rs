@@ -0,0 +1,207 @@
+use std::cell::OnceCell;
+
+use rmp_serde::decode;
+use serde::de::DeserializeOwned;
+use serde_json::error::Category;
+use tokio_tungstenite::tungstenite::Message;
+
+// Wire encoding for a connection, negotiated together with the protocol
+// version. Handlers always produce JSON text; the connection writer
+// transcodes it for binary encodings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Encoding {
+    pub const ALL: [Encoding; 3] = [Encoding::Json, Encoding::MessagePack, Encoding::Cbor];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::MessagePack => "msgpack",
+            Encoding::Cbor => "cbor",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|e| e.name() == name)
+    }
+
+    pub fn encode(&self, message: Message) -> Message {
+        let Message::Text(text) = &message else {
+            return message;
+        };
+        let value: serde_json::Value = match (self, serde_json::from_str(text)) {
+            (Encoding::Json, _) | (_, Err(_)) => return message,
+            (_, Ok(value)) => value,
+        };
+        let encoded = match self {
+            Encoding::MessagePack => rmp_serde::to_vec_named(&value).map_err(|e| e.to_string()),
+            Encoding::Cbor => {
+                let mut buffer = Vec::new();
+                ciborium::into_writer(&value, &mut buffer)
+                    .map(|_| buffer)
+                    .map_err(|e| e.to_string())
+            }
+            Encoding::Json => unreachable!(),
+        };
+        match encoded {
+            Ok(data) => Message::Binary(data.into()),
+            Err(_) => message,
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, DecodeError> {
+        match self {
+            Encoding::Json => serde_json::from_slice(data).map_err(|e| DecodeError {
+                message: e.to_string(),
+                semantic: e.classify() == Category::Data,
+                offset: None,
+            }),
+            Encoding::MessagePack => rmp_serde::from_slice(data).map_err(|e| {
+                let semantic = matches!(
+                    e,
+                    decode::Error::Syntax(_)
+                        | decode::Error::OutOfRange
+                        | decode::Error::LengthMismatch(_)
+                );
+                let message = match e {
+                    decode::Error::Syntax(message) => message,
+                    e => e.to_string(),
+                };
+                DecodeError {
+                    message,
+                    semantic,
+                    offset: None,
+                }
+            }),
+            Encoding::Cbor => ciborium::from_reader(data).map_err(|e| match e {
+                ciborium::de::Error::Semantic(offset, message) => DecodeError {
+                    message,
+                    semantic: true,
+                    offset,
+                },
+                ciborium::de::Error::Syntax(offset) => DecodeError {
+                    message: "invalid CBOR".to_owned(),
+                    semantic: false,
+                    offset: Some(offset),
+                },
+                e => DecodeError {
+                    message: format!("{:?}", e),
+                    semantic: false,
+                    offset: None,
+                },
+            }),
+        }
+    }
+}
+
+// Why a binary frame didn't decode. Semantic errors are well-formed data that
+// doesn't fit a client message, reported with serde's message so they get
+// the same codes as JSON ones.
+#[derive(Debug)]
+pub struct DecodeError {
+    pub message: String,
+    pub semantic: bool,
+    // Byte offset the decoder stopped at, when it knows it.
+    pub offset: Option<usize>,
+}
+
+// A message going to many connections, transcoded at most once per encoding.
+pub struct Encoded {
+    message: Message,
+    encoded: [OnceCell<Message>; Encoding::ALL.len()],
+}
+
+impl Encoded {
+    pub fn new(message: Message) -> Self {
+        Self {
+            message,
+            encoded: Default::default(),
+        }
+    }
+
+    pub fn get(&self, encoding: Encoding) -> Message {
+        self.encoded[encoding as usize]
+            .get_or_init(|| encoding.encode(self.message.clone()))
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(serde::Deserialize, serde::Serialize, Debug, PartialEq)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Request {
+        Ping { id: u32 },
+    }
+
+    fn cbor(value: &serde_json::Value) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        ciborium::into_writer(value, &mut buffer).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn round_trips_through_binary_encodings() {
+        let text = r#"{"type":"ping","id":7}"#;
+        for encoding in [Encoding::MessagePack, Encoding::Cbor] {
+            let Message::Binary(data) = encoding.encode(Message::text(text)) else {
+                panic!("{:?} should encode to binary", encoding);
+            };
+            assert_eq!(
+                encoding.decode::<Request>(&data).unwrap(),
+                Request::Ping { id: 7 }
+            );
+        }
+        assert_eq!(
+            Encoding::Json.encode(Message::text(text)),
+            Message::text(text)
+        );
+    }
+
+    #[test]
+    fn semantic_errors_keep_serde_messages() {
+        let unknown = serde_json::json!({"type":"pong"});
+        let missing = serde_json::json!({"type":"ping"});
+        let msgpack = |value| rmp_serde::to_vec_named(value).unwrap();
+        for (encoding, data, prefix) in [
+            (Encoding::MessagePack, msgpack(&unknown), "unknown variant"),
+            (Encoding::MessagePack, msgpack(&missing), "missing field"),
+            (Encoding::Cbor, cbor(&unknown), "unknown variant"),
+            (Encoding::Cbor, cbor(&missing), "missing field"),
+        ] {
+            let e = encoding.decode::<Request>(&data).unwrap_err();
+            assert!(e.semantic, "{:?}", e);
+            assert!(e.message.starts_with(prefix), "{:?}", e);
+        }
+    }
+
+    #[test]
+    fn malformed_data_is_not_semantic() {
+        // A map header with the map missing.
+        for (encoding, data) in [(Encoding::MessagePack, 0x81), (Encoding::Cbor, 0xa1)] {
+            let e = encoding.decode::<Request>(&[data]).unwrap_err();
+            assert!(!e.semantic, "{:?}: {:?}", encoding, e);
+        }
+    }
+
+    #[test]
+    fn encodes_once_per_encoding() {
+        let encoded = Encoded::new(Message::text(r#"{"type":"ping","id":1}"#));
+        let first = encoded.get(Encoding::Cbor);
+        assert!(matches!(first, Message::Binary(_)));
+        assert_eq!(encoded.get(Encoding::Cbor), first);
+        assert!(
+            encoded.encoded[Encoding::MessagePack as usize]
+                .get()
+                .is_none()
+        );
+        assert!(matches!(encoded.get(Encoding::Json), Message::Text(_)));
+    }
+}
@@ -1,13 +1,282 @@
-use crate::{app_state::AppState, handlers, types::ClientMessages};
+use crate::{
+    app_state::AppState,
+    compression::{Compressor, Deflate, DeflateStream},
+    encoding::Encoding,
+    handlers,
+    protocol::Protocol,
+    types::ClientMessages,
+};
 use futures::{SinkExt, StreamExt};
+use std::{
+    sync::{Arc, OnceLock},
+    time::Instant,
+};
 use tokio::net::TcpStream;
-use tokio::sync::mpsc::unbounded_channel;
-use tokio_tungstenite::{accept_async, tungstenite::Message};
+use tokio::sync::mpsc::{UnboundedSender, unbounded_channel};
+use tokio_tungstenite::{
+    accept_hdr_async_with_config,
+    tungstenite::{
+        Message,
+        handshake::server::{ErrorResponse, Request, Response},
+        http::{
+            HeaderValue, StatusCode,
+            header::{SEC_WEBSOCKET_EXTENSIONS, SEC_WEBSOCKET_PROTOCOL},
+        },
+        protocol::{CloseFrame, WebSocketConfig, frame::coding::CloseCode},
+    },
+};
 use tracing::{error, info, warn};
 use uuid::Uuid;
 
+fn query_param(request: &Request, name: &str) -> Option<String> {
+    request.uri().query()?.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        (key == name && !value.is_empty()).then(|| value.to_owned())
+    })
+}
+
+async fn dispatch(
+    app_state: &AppState,
+    user_id: &String,
+    session: &str,
+    connection_id: &str,
+    protocol: &Protocol,
+    tx: &UnboundedSender<Message>,
+    message: ClientMessages,
+) {
+    match message {
+        ClientMessages::Info => {
+            handlers::info::info(app_state, user_id, session, protocol, tx);
+        }
+
+        ClientMessages::Join { room } => {
+            handlers::room::join(app_state, user_id, &room, tx).await;
+        }
+        ClientMessages::Create {
+            room_name,
+            lifecycle,
+        } => {
+            handlers::room::create(app_state, user_id, &room_name, lifecycle, tx).await;
+        }
+        ClientMessages::GetRooms => {
+            handlers::room::get(app_state, user_id, protocol.version, tx).await;
+        }
+        ClientMessages::SendMessageToRoom {
+            message,
+            room,
+            content,
+            reply_to,
+        } => {
+            handlers::room::broadcast_message(
+                app_state,
+                message.clone(),
+                content,
+                room,
+                user_id.clone(),
+                reply_to,
+                None,
+                tx,
+            )
+            .await
+        }
+        ClientMessages::ListRoomMessages { room } => {
+            handlers::room::list_messages(app_state, user_id, &room, tx).await;
+        }
+        ClientMessages::RoomDetails { room } => {
+            handlers::room::details(app_state, user_id, tx, &room, protocol.version).await;
+        }
+        ClientMessages::LeaveRoom { room, user } => {
+            handlers::room::leave_room(app_state, tx, room, user).await;
+        }
+        ClientMessages::RenameRoom { room, room_name } => {
+            handlers::room::rename(app_state, user_id, room, room_name, tx).await;
+        }
+        ClientMessages::ArchiveRoom { room } => {
+            handlers::room::set_archived(app_state, user_id, room, true, tx).await;
+        }
+        ClientMessages::UnarchiveRoom { room } => {
+            handlers::room::set_archived(app_state, user_id, room, false, tx).await;
+        }
+        ClientMessages::ListArchivedRooms => {
+            handlers::room::list_archived(app_state, user_id, tx).await;
+        }
+        ClientMessages::SyncRoom { room } => {
+            handlers::room::sync(app_state, user_id, &room, tx).await;
+        }
+        ClientMessages::UpdateRoom { room, update } => {
+            handlers::room::update(app_state, user_id, room, update, tx).await;
+        }
+        ClientMessages::SearchMessages { search } => {
+            handlers::search::search(app_state, user_id, search, tx).await;
+        }
+        ClientMessages::StartUpload { upload } => {
+            handlers::upload::start(app_state, user_id, upload, tx).await;
+        }
+        ClientMessages::ResumeUpload { upload_id } => {
+            handlers::upload::resume(app_state, user_id, upload_id, tx).await;
+        }
+        ClientMessages::CancelUpload { upload_id } => {
+            handlers::upload::cancel(app_state, user_id, upload_id, tx).await;
+        }
+        ClientMessages::EditMessage {
+            room,
+            message_id,
+            message,
+            content,
+        } => {
+            handlers::message::edit(app_state, user_id, room, message_id, message, content, tx)
+                .await;
+        }
+        ClientMessages::DeleteMessage { room, message_id } => {
+            handlers::message::delete(app_state, user_id, room, message_id, tx).await;
+        }
+        ClientMessages::PinMessage {
+            room,
+            message_id,
+            pinned,
+        } => {
+            handlers::message::pin(app_state, user_id, room, message_id, pinned, tx).await;
+        }
+        ClientMessages::React {
+            room,
+            message_id,
+            emoji,
+        } => {
+            handlers::message::react(app_state, user_id, room, message_id, emoji, true, tx).await;
+        }
+        ClientMessages::Unreact {
+            room,
+            message_id,
+            emoji,
+        } => {
+            handlers::message::react(app_state, user_id, room, message_id, emoji, false, tx).await;
+        }
+        ClientMessages::ListThread {
+            room,
+            root,
+            after,
+            limit,
+        } => {
+            handlers::message::list_thread(app_state, user_id, room, root, after, limit, tx).await;
+        }
+        ClientMessages::DirectMessage { to, message } => {
+            handlers::direct::send(app_state, user_id, connection_id, to, message, tx).await;
+        }
+        ClientMessages::ListDirectMessages {
+            with,
+            before,
+            limit,
+        } => {
+            handlers::direct::list(app_state, user_id, with, before, limit, tx).await;
+        }
+        ClientMessages::ListConversations => {
+            handlers::direct::conversations(app_state, user_id, tx).await;
+        }
+        ClientMessages::TypingStart { room } => {
+            handlers::typing::start(app_state, user_id, room, tx).await;
+        }
+        ClientMessages::TypingStop { room } => {
+            if let Some(r) = app_state.find_room(&room).await {
+                handlers::typing::stop(app_state, &r, user_id).await;
+            }
+        }
+        ClientMessages::MarkRead { room, up_to_seq } => {
+            handlers::receipts::mark_read(app_state, user_id, room, up_to_seq, tx).await;
+        }
+        ClientMessages::SetReadReceipts { room, enabled } => {
+            handlers::receipts::set_read_receipts(app_state, user_id, room, enabled, tx).await;
+        }
+        ClientMessages::ListMentions { offset, limit } => {
+            handlers::mentions::list(app_state, user_id, offset, limit, tx).await;
+        }
+        ClientMessages::SetProfile {
+            display_name,
+            avatar,
+            status,
+        } => {
+            handlers::profile::set(app_state, user_id, display_name, avatar, status, tx).await;
+        }
+        ClientMessages::GetProfiles { users } => {
+            handlers::profile::get(app_state, users, tx).await;
+        }
+        ClientMessages::SetPresence { state } => {
+            handlers::presence::set(app_state, user_id, state, tx).await;
+        }
+        ClientMessages::GetPresence { users } => {
+            handlers::presence::get(app_state, users, tx).await;
+        }
+        ClientMessages::SubscribeRoomList {
+            filter,
+            sort,
+            limit,
+            cursor,
+        } => {
+            handlers::room_list::subscribe(
+                app_state,
+                user_id,
+                connection_id,
+                filter,
+                sort,
+                limit,
+                cursor,
+                tx,
+            )
+            .await;
+        }
+        ClientMessages::UnsubscribeRoomList => {
+            handlers::room_list::unsubscribe(app_state, connection_id).await;
+        }
+    }
+}
+
 pub async fn handle_connection(stream: TcpStream, app_state: AppState) {
-    let ws_stream = match accept_async(stream).await {
+    let mut requested_session = None;
+    let mut protocol = Protocol::default();
+    let deflate_config = &app_state.config.deflate;
+    let negotiated = Arc::new(OnceLock::new());
+    #[allow(clippy::result_large_err)]
+    let callback = |request: &Request, mut response: Response| {
+        requested_session = query_param(request, "session");
+        let offered = request
+            .headers()
+            .get(SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|offered| offered.to_str().ok());
+        let version = query_param(request, "protocol").and_then(|v| v.parse().ok());
+        protocol = match Protocol::negotiate(offered, version) {
+            Ok(protocol) => protocol,
+            Err(reason) => {
+                let mut rejection = ErrorResponse::new(Some(reason));
+                *rejection.status_mut() = StatusCode::BAD_REQUEST;
+                return Err(rejection);
+            }
+        };
+        let subprotocol = protocol.subprotocol();
+        if offered.is_some_and(|offered| offered.split(',').any(|p| p.trim() == subprotocol))
+            && let Ok(value) = HeaderValue::from_str(&subprotocol)
+        {
+            response.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, value);
+        }
+        if deflate_config.enabled
+            && let Some(offered) = request
+                .headers()
+                .get(SEC_WEBSOCKET_EXTENSIONS)
+                .and_then(|offered| offered.to_str().ok())
+            && let Some(deflate) = Deflate::negotiate(deflate_config, offered)
+            && let Ok(value) = HeaderValue::from_str(&deflate.response())
+        {
+            response
+                .headers_mut()
+                .insert(SEC_WEBSOCKET_EXTENSIONS, value);
+            let _ = negotiated.set(deflate);
+        }
+        Ok(response)
+    };
+    let max_frame_size = app_state.config.max_frame_size;
+    let stream = DeflateStream::new(stream, negotiated.clone(), max_frame_size);
+    let ws_config = WebSocketConfig::default()
+        .max_frame_size(Some(max_frame_size))
+        .max_message_size(Some(max_frame_size));
+    let ws_stream = match accept_hdr_async_with_config(stream, callback, Some(ws_config)).await {
         Ok(ws) => {
             info!("WebSocket handshake successful");
             ws
@@ -18,86 +287,110 @@ pub async fn handle_connection(stream: TcpStream, app_state: AppState) {
         }
     };
 
-    let user_id = Uuid::new_v4().to_string();
-    info!("User connected: {}", user_id);
+    let resumed = match &requested_session {
+        Some(session) => app_state
+            .resume_session(session)
+            .await
+            .map(|user| (user, session.clone())),
+        None => None,
+    };
+    let (user_id, session) = match resumed {
+        Some(resumed) => resumed,
+        None => {
+            let user_id = Uuid::new_v4().to_string();
+            let session = app_state.create_session(&user_id).await;
+            (user_id, session)
+        }
+    };
+    info!("User connected: {} ({})", user_id, protocol.subprotocol());
+    let encoding = protocol.encoding;
+    let mut compressor = negotiated
+        .get()
+        .map(|deflate| Compressor::new(*deflate, &app_state.config.deflate));
 
     let (mut write, mut read) = ws_stream.split();
     let (tx, mut rx) = unbounded_channel::<Message>();
 
+    let connection_id = Uuid::new_v4().to_string();
+    if app_state
+        .add_connection(&user_id, &connection_id, tx.clone(), encoding)
+        .await
     {
-        let mut conn = app_state.connections.lock().await;
-        conn.insert(user_id.clone(), tx.clone());
+        handlers::presence::connected(&app_state, &user_id).await;
     }
+    handlers::direct::deliver_queued(&app_state, &user_id, &tx).await;
 
     let user_id_clone = user_id.clone();
-    let connections_clone = app_state.connections.clone();
+    let connection_id_clone = connection_id.clone();
+    let app_state_clone = app_state.clone();
     tokio::spawn(async move {
         while let Some(msg) = rx.recv().await {
+            let mut msg = encoding.encode(msg);
+            if let Some(compressor) = compressor.as_mut() {
+                msg = compressor.compress(msg);
+            }
             if let Err(err) = write.send(msg).await {
                 error!(
                     "Error while sending message to {}: {:?}",
                     user_id_clone, err
                 );
-                connections_clone.lock().await.remove(&user_id_clone);
+                app_state_clone
+                    .remove_connection(&user_id_clone, &connection_id_clone)
+                    .await;
                 break;
             }
         }
     });
-    while let Some(message_result) = read.next().await {
-        match message_result {
+    let mut ping_interval = tokio::time::interval(app_state.config.ping_interval);
+    ping_interval.tick().await;
+    let mut last_seen = Instant::now();
+    let mut violations = 0;
+    loop {
+        let message_result = tokio::select! {
+            message = read.next() => match message {
+                Some(message) => message,
+                None => break,
+            },
+            _ = ping_interval.tick() => {
+                if last_seen.elapsed() >= app_state.config.idle_timeout {
+                    info!("Connection timed out for user: {}", user_id);
+                    let _ = tx.send(Message::Close(Some(CloseFrame {
+                        code: CloseCode::Away,
+                        reason: "idle timeout".into(),
+                    })));
+                    break;
+                }
+                if tx.send(Message::Ping(Default::default())).is_err() {
+                    break;
+                }
+                continue;
+            }
+        };
+        last_seen = Instant::now();
+
+        let parsed = match message_result {
             Ok(Message::Text(text)) => {
                 info!("Received from {}: {}", user_id, text);
-                let result = serde_json::from_str::<ClientMessages>(&text);
-                match result {
-                    Ok(message) => match message {
-                        ClientMessages::Info => {
-                            let _ = tx.send(Message::Text(
-                                serde_json::json!({
-                                    "type":"info",
-                                    "user_id":user_id.clone(),
-                                })
-                                .to_string()
-                                .into(),
-                            ));
-                        }
-
-                        ClientMessages::Join { room } => {
-                            handlers::room::join(&app_state, &user_id, &room, &tx).await;
-                        }
-                        ClientMessages::Create { room_name } => {
-                            handlers::room::create(&app_state, &user_id, &room_name, &tx).await;
-                        }
-                        ClientMessages::GetRooms => {
-                            handlers::room::get(&app_state, &tx).await;
-                        }
-                        ClientMessages::SendMessageToRoom { message, room } => {
-                            handlers::room::broadcast_message(
-                                &app_state,
-                                message.clone(),
-                                room,
-                                user_id.clone(),
-                            )
-                            .await
-                        }
-                        ClientMessages::ListRoomMessages { room } => {
-                            handlers::room::list_messages(&app_state, &room, &tx).await;
-                        }
-                        ClientMessages::RoomDetails { room } => {
-                            handlers::room::details(&app_state, &tx, &room).await;
-                        }
-                        ClientMessages::LeaveRoom { room, user } => {
-                            handlers::room::leave_room(&app_state, &tx, room, user).await;
-                        }
-                    },
-                    Err(e) => {
-                        warn!("Invalid message received from {}: {}", user_id, e);
-                        let echo = Message::Text(format!("Echo: {}", text).into());
-                        if let Err(e) = tx.send(echo) {
-                            error!("Failed to send echo: {}", e);
-                            break;
-                        }
-                    }
-                }
+                serde_json::from_str::<ClientMessages>(&text).map_err(|e| {
+                    handlers::send_protocol_error(&tx, &e);
+                    e.to_string()
+                })
+            }
+            Ok(Message::Binary(data)) if handlers::upload::is_chunk(&data) => {
+                handlers::upload::chunk(&app_state, &user_id, &data, &tx).await;
+                continue;
+            }
+            Ok(Message::Binary(data)) if encoding != Encoding::Json => {
+                info!(
+                    "Received {:?} frame from {}: {} bytes",
+                    encoding,
+                    user_id,
+                    data.len()
+                );
+                encoding.decode::<ClientMessages>(&data).map_err(|e| {
+                    handlers::send_decode_error(&tx, &e);
+                    e.message
+                })
             }
             Ok(Message::Binary(data)) => {
                 info!(
@@ -105,6 +398,7 @@ pub async fn handle_connection(stream: TcpStream, app_state: AppState) {
                     user_id,
                     data.len()
                 );
+                continue;
             }
             Ok(Message::Ping(data)) => {
                 info!("Received ping from {}", user_id);
@@ -112,23 +406,57 @@ pub async fn handle_connection(stream: TcpStream, app_state: AppState) {
                     info!("Failed to send pong: {}", e);
                     break;
                 }
+                continue;
             }
+            Ok(Message::Pong(_)) => continue,
             Ok(Message::Close(_)) => {
                 info!("Connection closed by client: {}", user_id);
                 break;
             }
-            Ok(_) => {}
+            Ok(_) => continue,
             Err(e) => {
                 error!("WebSocket error for {}: {}", user_id, e);
                 break;
             }
+        };
+
+        match parsed {
+            Ok(message) => {
+                handlers::presence::touch(&app_state, &user_id).await;
+                dispatch(
+                    &app_state,
+                    &user_id,
+                    &session,
+                    &connection_id,
+                    &protocol,
+                    &tx,
+                    message,
+                )
+                .await;
+            }
+            Err(e) => {
+                warn!("Invalid message received from {}: {}", user_id, e);
+                violations += 1;
+                if violations >= app_state.config.max_protocol_violations {
+                    warn!("Too many protocol violations from {}", user_id);
+                    let _ = tx.send(Message::Close(Some(CloseFrame {
+                        code: CloseCode::Policy,
+                        reason: "too many protocol violations".into(),
+                    })));
+                    break;
+                }
+            }
         }
     }
 
-    let mut connections_guard = app_state.connections.lock().await;
-    app_state.remove_from_rooms(user_id.clone()).await;
-    connections_guard.remove(&user_id);
-    info!("Removed user {} from connections", user_id);
+    handlers::room_list::unsubscribe(&app_state, &connection_id).await;
+    let last_connection = app_state.remove_connection(&user_id, &connection_id).await;
+    info!("Removed connection {} of user {}", connection_id, user_id);
+    if last_connection {
+        handlers::presence::disconnected(&app_state, &user_id).await;
+        handlers::room::leave_all(&app_state, &user_id).await;
+        info!("Removed user {} from connections", user_id);
+    }
 
     {
         let rooms = app_state.get_rooms().await;
@@ -0,0 +1,143 @@
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{error, info};
+
+use crate::{app_state::AppState, handlers::send_error, types::DirectMessage};
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 100;
+
+fn direct_message_event(message: &DirectMessage) -> Message {
+    Message::Text(
+        serde_json::json!({
+            "type":"direct_message",
+            "id":message.id,
+            "from":message.from,
+            "to":message.to,
+            "message":message.message,
+            "sent_at":message.sent_at
+        })
+        .to_string()
+        .into(),
+    )
+}
+
+pub async fn send(
+    app_state: &AppState,
+    user_id: &String,
+    connection_id: &str,
+    to: String,
+    message: String,
+    tx: &UnboundedSender<Message>,
+) {
+    if to == *user_id {
+        send_error(
+            tx,
+            "invalid_recipient",
+            "Cannot send a direct message to yourself",
+        );
+        return;
+    }
+    if message.is_empty() || message.len() > app_state.config.max_message_size {
+        send_error(tx, "invalid_message", "Message is empty or too long");
+        return;
+    }
+
+    let direct_message = DirectMessage::new(user_id.clone(), to.clone(), message);
+    app_state.add_direct_message(&direct_message).await;
+
+    let delivered = app_state
+        .send_to_user(&to, direct_message_event(&direct_message))
+        .await;
+    if !delivered {
+        info!("User {} is offline, queueing direct message", to);
+        app_state.queue_direct_message(&direct_message).await;
+    }
+    // The sender's other devices show the conversation too.
+    app_state
+        .send_to_other_devices(
+            user_id,
+            connection_id,
+            direct_message_event(&direct_message),
+        )
+        .await;
+
+    if let Err(e) = tx.send(Message::Text(
+        serde_json::json!({
+            "type":"direct_message_sent",
+            "id":direct_message.id,
+            "to":to,
+            "sent_at":direct_message.sent_at,
+            "delivered":delivered
+        })
+        .to_string()
+        .into(),
+    )) {
+        error!("Error while sending direct message ack: {:?}", e);
+    }
+}
+
+pub async fn list(
+    app_state: &AppState,
+    user_id: &str,
+    with: String,
+    before: Option<usize>,
+    limit: Option<usize>,
+    tx: &UnboundedSender<Message>,
+) {
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let count = app_state.direct_message_count(user_id, &with).await;
+    let end = before.unwrap_or(count).min(count);
+    let start = end.saturating_sub(limit);
+    let messages = if end > start {
+        app_state
+            .get_direct_messages(user_id, &with, start, end - 1)
+            .await
+    } else {
+        vec![]
+    };
+
+    if let Err(e) = tx.send(Message::Text(
+        serde_json::json!({
+            "type":"direct_messages",
+            "with":with,
+            "messages":messages,
+            "before":if start > 0 { Some(start) } else { None }
+        })
+        .to_string()
+        .into(),
+    )) {
+        error!("Error while sending direct messages: {:?}", e);
+    }
+}
+
+pub async fn conversations(app_state: &AppState, user_id: &str, tx: &UnboundedSender<Message>) {
+    let conversations = app_state.get_conversations(user_id).await;
+    if let Err(e) = tx.send(Message::Text(
+        serde_json::json!({
+            "type":"conversations",
+            "conversations":conversations
+        })
+        .to_string()
+        .into(),
+    )) {
+        error!("Error while sending conversations: {:?}", e);
+    }
+}
+
+pub async fn deliver_queued(app_state: &AppState, user_id: &String, tx: &UnboundedSender<Message>) {
+    let queued = app_state.take_queued_direct_messages(user_id).await;
+    if !queued.is_empty() {
+        info!(
+            "Delivering {} queued direct messages to {}",
+            queued.len(),
+            user_id
+        );
+    }
+    for message in queued.iter() {
+        if let Err(e) = tx.send(direct_message_event(message)) {
+            error!("Error while delivering queued direct message: {:?}", e);
+            app_state.queue_direct_message(message).await;
+        }
+    }
+}
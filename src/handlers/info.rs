@@ -0,0 +1,56 @@
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::error;
+
+use crate::{
+    app_state::AppState,
+    encoding::Encoding,
+    protocol::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, Protocol},
+};
+
+pub fn info(
+    app_state: &AppState,
+    user_id: &str,
+    session: &str,
+    protocol: &Protocol,
+    tx: &UnboundedSender<Message>,
+) {
+    let config = &app_state.config;
+    let response = serde_json::json!({
+        "type":"info",
+        "user_id":user_id,
+        "session":session,
+        "server_version":env!("CARGO_PKG_VERSION"),
+        "protocol_version":protocol.version,
+        "supported_protocol_versions":(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).collect::<Vec<_>>(),
+        "encoding":protocol.encoding.name(),
+        "encodings":Encoding::ALL.iter().map(|e| e.name()).collect::<Vec<_>>(),
+        "features":{
+            "direct_messages":true,
+            "threads":true,
+            "reactions":true,
+            "mentions":true,
+            "profiles":true,
+            "presence":true,
+            "typing":true,
+            "search":true,
+            "attachments":true,
+            "read_receipts":true,
+            "permessage_deflate":config.deflate.enabled,
+            "sfu":false,
+            "recording":false
+        },
+        "limits":{
+            "max_message_size":config.max_message_size,
+            "max_room_size":config.max_room_size,
+            "max_upload_size":config.max_upload_size,
+            "upload_chunk_size":config.upload_chunk_size,
+            "allowed_mime_types":config.allowed_mime_types,
+            "thumbnail_size":config.thumbnail_size
+        },
+        "ice_servers":config.ice_servers
+    });
+    if let Err(e) = tx.send(Message::Text(response.to_string().into())) {
+        error!("Error while sending info to {}: {:?}", user_id, e);
+    }
+}
@@ -0,0 +1,95 @@
+use std::{collections::HashMap, time::Instant};
+
+use tracing::info;
+
+use crate::{
+    app_state::AppState,
+    handlers::{room::broadcast_to_unsubscribed, room_list},
+    types::{Room, RoomLifecycle},
+    utils::now,
+};
+
+// Why a room is due for deletion, if it is.
+fn expired(
+    app_state: &AppState,
+    room: &Room,
+    empty_since: &HashMap<String, Instant>,
+) -> Option<&'static str> {
+    if let Some(archived_at) = room.archived_at {
+        let retention = app_state.config.archive_retention.as_millis() as u64;
+        return (retention > 0 && now() >= archived_at.saturating_add(retention))
+            .then_some("purged");
+    }
+    match room.lifecycle {
+        RoomLifecycle::Persistent => None,
+        RoomLifecycle::Ephemeral => empty_since
+            .get(&room.room)
+            .is_some_and(|since| since.elapsed() >= app_state.config.room_grace_period)
+            .then_some("empty"),
+        RoomLifecycle::Ttl { hours } => {
+            let last_activity = room.last_activity().max(room.created_at);
+            let ttl = hours.saturating_mul(60 * 60 * 1000);
+            (now() >= last_activity.saturating_add(ttl)).then_some("expired")
+        }
+    }
+}
+
+// Deletes rooms according to their lifecycle, and archived rooms once the
+// archive retention has passed. A room counts as empty when none of its
+// members is connected, which also covers members that were never removed
+// because the server went down with them in the room.
+pub async fn reaper(app_state: AppState) {
+    let mut interval = tokio::time::interval(app_state.config.room_reaper_interval);
+    let mut empty_since: HashMap<String, Instant> = HashMap::new();
+    loop {
+        interval.tick().await;
+        let rooms = app_state.get_rooms().await;
+        {
+            let connections = app_state.connections.lock().await;
+            empty_since.retain(|room, _| rooms.iter().any(|r| r.room == *room));
+            for room in &rooms {
+                if room.users.iter().any(|u| connections.contains_key(u)) {
+                    empty_since.remove(&room.room);
+                } else {
+                    empty_since
+                        .entry(room.room.clone())
+                        .or_insert_with(Instant::now);
+                }
+            }
+        }
+
+        for snapshot in &rooms {
+            if expired(&app_state, snapshot, &empty_since).is_none() {
+                continue;
+            }
+            // The snapshot may be stale by now, so the room's current state
+            // decides, checked with room writes held off.
+            let mut reason = None;
+            let Some(room) = app_state
+                .del_room_if(&snapshot.room, |room| {
+                    reason = expired(&app_state, room, &empty_since);
+                    reason.is_some()
+                })
+                .await
+            else {
+                continue;
+            };
+            let reason = reason.unwrap_or_default();
+            empty_since.remove(&room.room);
+            info!("Room {} deleted ({})", room.room, reason);
+            room_list::refresh(&app_state, &room.room).await;
+            broadcast_to_unsubscribed(
+                &app_state,
+                &room,
+                serde_json::json!({
+                    "type":"room_deleted",
+                    "room":room.room,
+                    "reason":reason,
+                    "version":room.version + 1
+                })
+                .to_string(),
+            )
+            .await;
+        }
+    }
+}
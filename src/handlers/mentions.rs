@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{error, info};
+
+use crate::{
+    app_state::{AppState, MAX_MENTIONS},
+    types::{Mention, MentionEntry, Room, RoomMessage},
+};
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 100;
+
+pub fn is_mention_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '-' || c == '_'
+}
+
+// Returns the members mentioned by `@<user>` and whether `@room` was used.
+// An `@` only starts a mention at the beginning of a word, so addresses like
+// `someone@example.com` are left alone.
+pub fn parse(message: &str, members: &[String]) -> (Vec<String>, bool) {
+    let mut mentions: Vec<String> = vec![];
+    let mut room = false;
+    let mut previous = None;
+    for (i, c) in message.char_indices() {
+        let at_word_start = previous.is_none_or(|p: char| !is_mention_char(p));
+        previous = Some(c);
+        if c != '@' || !at_word_start {
+            continue;
+        }
+        let rest = &message[i + 1..];
+        let end = rest.find(|c| !is_mention_char(c)).unwrap_or(rest.len());
+        let name = &rest[..end];
+        if name == "room" {
+            room = true;
+        } else if let Some(member) = members.iter().find(|m| *m == name)
+            && !mentions.contains(member)
+        {
+            mentions.push(member.clone());
+        }
+    }
+    (mentions, room)
+}
+
+fn resolve(room: &Room, message: &RoomMessage, everyone: bool) -> Mention {
+    Mention {
+        room: room.room.clone(),
+        room_name: room.room_name.clone(),
+        message_id: message.id.clone(),
+        seq: message.seq,
+        by: message.by.clone(),
+        message: message.message.clone(),
+        sent_at: message.sent_at,
+        everyone,
+    }
+}
+
+pub async fn notify(app_state: &AppState, room: &Room, message: &RoomMessage) {
+    let recipients: Vec<&String> = if message.mentions_room {
+        room.users.iter().collect()
+    } else {
+        message.mentions.iter().collect()
+    };
+
+    for user in recipients.into_iter().filter(|u| **u != message.by) {
+        let everyone = !message.mentions.contains(user);
+        app_state
+            .add_mention(
+                user,
+                &MentionEntry {
+                    room: room.room.clone(),
+                    message_id: message.id.clone(),
+                    seq: message.seq,
+                    everyone,
+                },
+            )
+            .await;
+        let mention = resolve(room, message, everyone);
+
+        let mut event = serde_json::to_value(&mention).unwrap();
+        event["type"] = "mentioned".into();
+        if !app_state
+            .send_to_user(user, Message::Text(event.to_string().into()))
+            .await
+        {
+            info!("User {} is offline, mention kept in inbox", user);
+        }
+    }
+}
+
+pub async fn list(
+    app_state: &AppState,
+    user_id: &str,
+    offset: Option<usize>,
+    limit: Option<usize>,
+    tx: &UnboundedSender<Message>,
+) {
+    // The inbox never holds more than `MAX_MENTIONS`.
+    let offset = offset.unwrap_or(0).min(MAX_MENTIONS);
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let entries = app_state
+        .get_mentions(user_id, offset, offset + limit - 1)
+        .await;
+    let next = (entries.len() == limit).then_some(offset + limit);
+
+    // Entries whose message was deleted, pruned or edited to drop the
+    // mention are skipped, as are rooms the user can no longer read.
+    let rooms: HashMap<String, Room> = app_state
+        .get_rooms()
+        .await
+        .into_iter()
+        .map(|r| (r.room.clone(), r))
+        .collect();
+    let mentions: Vec<Mention> = entries
+        .iter()
+        .filter_map(|entry| {
+            let room = rooms.get(&entry.room).filter(|r| r.can_read(user_id))?;
+            let message = room
+                .messages
+                .iter()
+                .find(|m| m.id == entry.message_id && !m.deleted)?;
+            let mentioned = message.mentions.iter().any(|u| u == user_id)
+                || (message.mentions_room && room.users.iter().any(|u| u == user_id));
+            mentioned.then(|| resolve(room, &message.redacted(), entry.everyone))
+        })
+        .collect();
+
+    if let Err(e) = tx.send(Message::Text(
+        serde_json::json!({
+            "type":"mentions",
+            "mentions":mentions,
+            "next":next
+        })
+        .to_string()
+        .into(),
+    )) {
+        error!("Error while sending mentions: {:?}", e);
+    }
+}
@@ -0,0 +1,491 @@
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{error, info};
+
+use crate::{
+    app_state::AppState,
+    handlers::{
+        self,
+        room::{broadcast_to_room, ensure_writable},
+        send_error,
+    },
+    rich_text,
+    types::{MessageRevision, Room, RoomMessage},
+    utils::now,
+};
+
+const MAX_EMOJI_LEN: usize = 32;
+const MAX_SHORTCODE_LEN: usize = 32;
+// Distinct reactions on one message, and how many of them one user can add.
+const MAX_REACTIONS_PER_MESSAGE: usize = 20;
+const MAX_REACTIONS_PER_USER: usize = 10;
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 100;
+
+// Code points that can stand alone in an emoji sequence.
+fn pictographic(c: char) -> bool {
+    matches!(
+        c,
+        '\u{a9}'
+            | '\u{ae}'
+            | '\u{203c}'
+            | '\u{2049}'
+            | '\u{2122}'
+            | '\u{2139}'
+            | '\u{2194}'..='\u{21ff}'
+            | '\u{2300}'..='\u{23ff}'
+            | '\u{24c2}'
+            | '\u{25aa}'..='\u{25fe}'
+            | '\u{2600}'..='\u{27bf}'
+            | '\u{2934}'
+            | '\u{2935}'
+            | '\u{2b00}'..='\u{2bff}'
+            | '\u{3030}'
+            | '\u{303d}'
+            | '\u{3297}'
+            | '\u{3299}'
+            | '\u{1f000}'..='\u{1faff}'
+    )
+}
+
+// Code points that only modify or join the pictographs around them: zero
+// width joiner, variation selectors, keycap and tag characters.
+fn emoji_modifier(c: char) -> bool {
+    matches!(
+        c,
+        '\u{200d}' | '\u{fe0e}' | '\u{fe0f}' | '\u{20e3}' | '\u{e0020}'..='\u{e007f}'
+    )
+}
+
+// A reaction is either a Unicode emoji sequence or a `:shortcode:` for a
+// custom one.
+fn valid_emoji(emoji: &str) -> bool {
+    if let Some(name) = emoji
+        .strip_prefix(':')
+        .and_then(|rest| rest.strip_suffix(':'))
+    {
+        return !name.is_empty()
+            && name.len() <= MAX_SHORTCODE_LEN
+            && name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "_+-".contains(c));
+    }
+    // Keycaps are the only sequences built on plain ASCII: 1️⃣, #️⃣, *️⃣.
+    let keycap = emoji.ends_with('\u{20e3}');
+    emoji.len() <= MAX_EMOJI_LEN
+        && emoji.chars().any(|c| pictographic(c) || keycap)
+        && emoji.chars().all(|c| {
+            pictographic(c)
+                || emoji_modifier(c)
+                || (keycap && (c.is_ascii_digit() || c == '#' || c == '*'))
+        })
+}
+
+// Why adding `user`'s reaction would go over the limits, if it would.
+fn reaction_limit(message: &RoomMessage, emoji: &str, user: &str) -> Option<&'static str> {
+    if !message.reactions.contains_key(emoji)
+        && message.reactions.len() >= MAX_REACTIONS_PER_MESSAGE
+    {
+        return Some("Message has too many different reactions");
+    }
+    let own = message
+        .reactions
+        .values()
+        .filter(|users| users.iter().any(|u| u == user))
+        .count();
+    (own >= MAX_REACTIONS_PER_USER).then_some("Too many reactions on this message")
+}
+
+async fn find_message(
+    app_state: &AppState,
+    room: &str,
+    message_id: &str,
+    tx: &UnboundedSender<Message>,
+) -> Option<(Room, RoomMessage)> {
+    let Some(r) = app_state.find_room(room).await else {
+        send_error(tx, "not_found", "Room not found");
+        return None;
+    };
+    if !ensure_writable(&r, tx) {
+        return None;
+    }
+    let Some(message) = r.messages.iter().find(|m| m.id == message_id).cloned() else {
+        send_error(tx, "not_found", "Message not found");
+        return None;
+    };
+    Some((r, message))
+}
+
+pub async fn edit(
+    app_state: &AppState,
+    user_id: &String,
+    room: String,
+    message_id: String,
+    message: String,
+    content: Option<String>,
+    tx: &UnboundedSender<Message>,
+) {
+    let max_size = app_state.config.max_message_size;
+    if message.len() > max_size || content.as_ref().is_some_and(|c| c.len() > max_size) {
+        send_error(tx, "message_too_large", "Message is too large");
+        return;
+    }
+    let Some((r, existing)) = find_message(app_state, &room, &message_id, tx).await else {
+        return;
+    };
+    if existing.by != *user_id {
+        send_error(tx, "forbidden", "Only the author can edit a message");
+        return;
+    }
+    if existing.deleted {
+        send_error(tx, "message_deleted", "Message has been deleted");
+        return;
+    }
+    if content.is_none() && message.trim().is_empty() && existing.attachment.is_none() {
+        send_error(tx, "empty_message", "Message is empty");
+        return;
+    }
+    let content = match content.map(|c| rich_text::parse(&c, &r.users)).transpose() {
+        Ok(content) => content,
+        Err(reason) => {
+            send_error(tx, "invalid_content", reason);
+            return;
+        }
+    };
+    let message = content.as_deref().map_or(message, rich_text::plain_text);
+    let (mentions, mentions_room) = match &content {
+        Some(blocks) => rich_text::mentions(blocks),
+        None => handlers::mentions::parse(&message, &r.users),
+    };
+
+    let edited_at = now();
+    let updated = app_state
+        .update_message(room.clone(), &message_id, |m| {
+            m.history.push(MessageRevision {
+                message: std::mem::replace(&mut m.message, message),
+                content: std::mem::replace(&mut m.content, content),
+                edited_at: m.edited_at.unwrap_or(m.sent_at),
+            });
+            m.edited_at = Some(edited_at);
+            m.mentions = mentions;
+            m.mentions_room = mentions_room;
+        })
+        .await;
+
+    if let Some(m) = updated {
+        info!("User {} edited message {} in {}", user_id, message_id, room);
+        broadcast_to_room(
+            app_state,
+            &r,
+            serde_json::json!({
+                "type":"message_edited",
+                "room":room,
+                "message_id":message_id,
+                "message":m.message,
+                "content":m.content,
+                "edited_at":edited_at,
+                "by":m.by
+            })
+            .to_string(),
+        )
+        .await;
+    }
+}
+
+pub async fn delete(
+    app_state: &AppState,
+    user_id: &String,
+    room: String,
+    message_id: String,
+    tx: &UnboundedSender<Message>,
+) {
+    let Some((r, existing)) = find_message(app_state, &room, &message_id, tx).await else {
+        return;
+    };
+    if existing.by != *user_id && !r.is_moderator(user_id) {
+        send_error(tx, "forbidden", "Not allowed to delete this message");
+        return;
+    }
+    if existing.deleted {
+        return;
+    }
+
+    let deleted_at = now();
+    let updated = app_state
+        .update_message(room.clone(), &message_id, |m| {
+            m.history.push(MessageRevision {
+                message: std::mem::take(&mut m.message),
+                content: m.content.take(),
+                edited_at: m.edited_at.unwrap_or(m.sent_at),
+            });
+            m.edited_at = Some(deleted_at);
+            m.deleted = true;
+            m.deleted_by = Some(user_id.clone());
+            m.pinned = false;
+        })
+        .await;
+
+    if updated.is_some() {
+        info!(
+            "User {} deleted message {} in {}",
+            user_id, message_id, room
+        );
+        broadcast_to_room(
+            app_state,
+            &r,
+            serde_json::json!({
+                "type":"message_deleted",
+                "room":room,
+                "message_id":message_id,
+                "deleted_by":user_id,
+                "deleted_at":deleted_at
+            })
+            .to_string(),
+        )
+        .await;
+    }
+}
+
+pub async fn pin(
+    app_state: &AppState,
+    user_id: &String,
+    room: String,
+    message_id: String,
+    pinned: bool,
+    tx: &UnboundedSender<Message>,
+) {
+    let Some((r, existing)) = find_message(app_state, &room, &message_id, tx).await else {
+        return;
+    };
+    if !r.is_moderator(user_id) {
+        send_error(tx, "forbidden", "Only moderators can pin messages");
+        return;
+    }
+    if existing.deleted {
+        send_error(tx, "message_deleted", "Message has been deleted");
+        return;
+    }
+
+    let updated = app_state
+        .update_message(room.clone(), &message_id, |m| m.pinned = pinned)
+        .await;
+
+    if updated.is_some() {
+        broadcast_to_room(
+            app_state,
+            &r,
+            serde_json::json!({
+                "type":"message_pinned",
+                "room":room,
+                "message_id":message_id,
+                "pinned":pinned,
+                "by":user_id
+            })
+            .to_string(),
+        )
+        .await;
+    }
+}
+
+pub async fn react(
+    app_state: &AppState,
+    user_id: &String,
+    room: String,
+    message_id: String,
+    emoji: String,
+    add: bool,
+    tx: &UnboundedSender<Message>,
+) {
+    if !valid_emoji(&emoji) {
+        send_error(tx, "invalid_emoji", "Invalid reaction");
+        return;
+    }
+    let Some((r, existing)) = find_message(app_state, &room, &message_id, tx).await else {
+        return;
+    };
+    if !r.users.contains(user_id) {
+        send_error(tx, "forbidden", "Not a member of this room");
+        return;
+    }
+    if existing.deleted {
+        send_error(tx, "message_deleted", "Message has been deleted");
+        return;
+    }
+
+    // Limits are checked against the stored message so concurrent reactions
+    // can't slip past them.
+    let mut rejected = None;
+    let updated = app_state
+        .update_message(room.clone(), &message_id, |m| {
+            let reacted = m
+                .reactions
+                .get(&emoji)
+                .is_some_and(|users| users.contains(user_id));
+            if add && !reacted {
+                rejected = reaction_limit(m, &emoji, user_id);
+                if rejected.is_some() {
+                    return;
+                }
+            }
+            let users = m.reactions.entry(emoji.clone()).or_default();
+            if add && !users.contains(user_id) {
+                users.push(user_id.clone());
+            } else if !add {
+                users.retain(|u| u != user_id);
+            }
+            if users.is_empty() {
+                m.reactions.remove(&emoji);
+            }
+        })
+        .await;
+    if let Some(reason) = rejected {
+        send_error(tx, "too_many_reactions", reason);
+        return;
+    }
+
+    if let Some(m) = updated {
+        let users = m.reactions.get(&emoji).cloned().unwrap_or_default();
+        broadcast_to_room(
+            app_state,
+            &r,
+            serde_json::json!({
+                "type":"reaction_updated",
+                "room":room,
+                "message_id":message_id,
+                "emoji":emoji,
+                "count":users.len(),
+                "users":users,
+                "by":user_id,
+                "added":add
+            })
+            .to_string(),
+        )
+        .await;
+    }
+}
+
+pub async fn list_thread(
+    app_state: &AppState,
+    user_id: &str,
+    room: String,
+    root: String,
+    after: Option<String>,
+    limit: Option<usize>,
+    tx: &UnboundedSender<Message>,
+) {
+    let Some(r) = app_state.find_room(&room).await else {
+        send_error(tx, "not_found", "Room not found");
+        return;
+    };
+    if !r.can_read(user_id) {
+        send_error(tx, "forbidden", "Not a member of this room");
+        return;
+    }
+    let Some(root_message) = r.messages.iter().find(|m| m.id == root).cloned() else {
+        send_error(tx, "not_found", "Message not found");
+        return;
+    };
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let replies: Vec<&RoomMessage> = r
+        .messages
+        .iter()
+        .filter(|m| m.thread_root.as_ref() == Some(&root))
+        .collect();
+    let start = match after {
+        Some(after) => replies
+            .iter()
+            .position(|m| m.id == after)
+            .map_or(0, |i| i + 1),
+        None => 0,
+    };
+
+    let page: Vec<RoomMessage> = replies
+        .iter()
+        .skip(start)
+        .take(limit)
+        .map(|m| m.redacted())
+        .collect();
+    let next = if start + page.len() < replies.len() {
+        page.last().map(|m| m.id.clone())
+    } else {
+        None
+    };
+
+    let mut authors = vec![root_message.by.clone()];
+    authors.extend(page.iter().map(|m| m.by.clone()));
+    let profiles = app_state.get_profiles(&authors).await;
+
+    if let Err(e) = tx.send(Message::Text(
+        serde_json::json!({
+            "type":"thread",
+            "room":room,
+            "root":root_message.redacted(),
+            "replies":page,
+            "next":next,
+            "profiles":profiles
+        })
+        .to_string()
+        .into(),
+    )) {
+        error!("Error while sending thread {}: {:?}", root, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_emoji_sequences_and_shortcodes() {
+        for emoji in [
+            "👍",
+            "❤️",
+            "👍🏽",
+            "👩\u{200d}💻",
+            "🇫🇷",
+            "1\u{fe0f}\u{20e3}",
+            ":party_parrot:",
+            ":+1:",
+        ] {
+            assert!(valid_emoji(emoji), "{emoji:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_text_and_bare_modifiers() {
+        for emoji in [
+            "",
+            "lol",
+            "a👍",
+            "<b>",
+            "\u{fe0f}",
+            "\u{200d}",
+            "1",
+            "::",
+            ":Party:",
+            ":a b:",
+            &"👍".repeat(9),
+        ] {
+            assert!(!valid_emoji(emoji), "{emoji:?}");
+        }
+    }
+
+    #[test]
+    fn caps_reactions() {
+        let mut message = RoomMessage::new("a".to_owned(), String::new());
+        for i in 0..MAX_REACTIONS_PER_USER {
+            message
+                .reactions
+                .insert(format!(":e{i}:"), vec!["a".to_owned()]);
+        }
+        assert!(reaction_limit(&message, ":new:", "a").is_some());
+        assert!(reaction_limit(&message, ":new:", "b").is_none());
+        for i in MAX_REACTIONS_PER_USER..MAX_REACTIONS_PER_MESSAGE {
+            message
+                .reactions
+                .insert(format!(":e{i}:"), vec!["c".to_owned()]);
+        }
+        assert!(reaction_limit(&message, ":new:", "b").is_some());
+        assert!(reaction_limit(&message, ":e0:", "b").is_none());
+    }
+}
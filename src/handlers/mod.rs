@@ -1,2 +1,97 @@
 pub mod connections;
+pub mod direct;
+pub mod info;
+pub mod lifecycle;
+pub mod mentions;
+pub mod message;
+pub mod presence;
+pub mod profile;
+pub mod receipts;
+pub mod retention;
 pub mod room;
+pub mod room_list;
+pub mod search;
+pub mod typing;
+pub mod upload;
+
+use serde_json::error::Category;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::error;
+
+use crate::{
+    encoding::{DecodeError, Encoded},
+    types::Devices,
+};
+
+pub fn send_error(tx: &UnboundedSender<Message>, code: &str, message: &str) {
+    let response = serde_json::json!({
+        "type": "error",
+        "code": code,
+        "message": message
+    });
+    if let Err(e) = tx.send(Message::Text(response.to_string().into())) {
+        error!("Error while sending error {}: {:?}", code, e);
+    }
+}
+
+// Tells the client why its frame was rejected, with the position serde
+// stopped at so malformed payloads are easy to track down.
+pub fn send_protocol_error(tx: &UnboundedSender<Message>, e: &serde_json::Error) {
+    let message = e.to_string();
+    let code = match e.classify() {
+        Category::Data => data_error_code(&message),
+        Category::Syntax | Category::Eof | Category::Io => "parse_error",
+    };
+    let response = serde_json::json!({
+        "type": "error",
+        "code": code,
+        "message": message,
+        "line": e.line(),
+        "column": e.column()
+    });
+    if let Err(e) = tx.send(Message::Text(response.to_string().into())) {
+        error!("Error while sending error {}: {:?}", code, e);
+    }
+}
+
+// serde's messages are the same whichever format the data came in.
+fn data_error_code(message: &str) -> &'static str {
+    if message.starts_with("unknown variant") {
+        "unknown_type"
+    } else if message.starts_with("missing field") {
+        "missing_field"
+    } else {
+        "invalid_field"
+    }
+}
+
+// The binary counterpart of `send_protocol_error`, with a byte offset in
+// place of the line and column.
+pub fn send_decode_error(tx: &UnboundedSender<Message>, e: &DecodeError) {
+    let code = if e.semantic {
+        data_error_code(&e.message)
+    } else {
+        "parse_error"
+    };
+    let response = serde_json::json!({
+        "type": "error",
+        "code": code,
+        "message": e.message,
+        "offset": e.offset
+    });
+    if let Err(e) = tx.send(Message::Text(response.to_string().into())) {
+        error!("Error while sending error {}: {:?}", code, e);
+    }
+}
+
+pub fn send_to_devices(devices: Option<&Devices>, message: &Encoded) -> bool {
+    let mut delivered = false;
+    for (connection, device) in devices.into_iter().flatten() {
+        match device.tx.send(message.get(device.encoding)) {
+            Ok(()) => delivered = true,
+            Err(err) => error!("Error while sending message to connection {connection}: {err:?}"),
+        }
+    }
+    delivered
+}
@@ -0,0 +1,201 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{error, info};
+
+use crate::{
+    app_state::AppState,
+    handlers::{room::send_to_users, send_error},
+    types::{PresenceEntry, PresenceState},
+    utils::now,
+};
+
+const AWAY_AFTER: Duration = Duration::from_secs(5 * 60);
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const LAST_SEEN_PERSIST_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_USERS_PER_REQUEST: usize = 100;
+
+async fn notify_peers(app_state: &AppState, user_id: &str, state: PresenceState, last_seen: u64) {
+    let peers = app_state.room_peers(user_id).await;
+    send_to_users(
+        app_state,
+        &peers,
+        serde_json::json!({
+            "type":"presence",
+            "user":user_id,
+            "state":state,
+            "last_seen":last_seen
+        })
+        .to_string(),
+    )
+    .await;
+}
+
+pub async fn connected(app_state: &AppState, user_id: &str) {
+    let now_instant = Instant::now();
+    app_state.presence.lock().await.insert(
+        user_id.to_owned(),
+        PresenceEntry {
+            state: PresenceState::Online,
+            manual: None,
+            last_active: now_instant,
+            last_persisted: now_instant,
+        },
+    );
+    let last_seen = now();
+    app_state.set_last_seen(user_id, last_seen).await;
+    notify_peers(app_state, user_id, PresenceState::Online, last_seen).await;
+}
+
+pub async fn disconnected(app_state: &AppState, user_id: &str) {
+    let visible = app_state
+        .presence
+        .lock()
+        .await
+        .remove(user_id)
+        .is_some_and(|entry| entry.effective() != PresenceState::Offline);
+    let last_seen = now();
+    app_state.set_last_seen(user_id, last_seen).await;
+    if visible {
+        notify_peers(app_state, user_id, PresenceState::Offline, last_seen).await;
+    }
+}
+
+// Called for every message the user sends; brings an idle user back online
+// and keeps their last-seen timestamp reasonably fresh.
+pub async fn touch(app_state: &AppState, user_id: &str) {
+    let now_instant = Instant::now();
+    let (came_back, persist) = {
+        let mut presence = app_state.presence.lock().await;
+        let Some(entry) = presence.get_mut(user_id) else {
+            return;
+        };
+        entry.last_active = now_instant;
+        let came_back = entry.state == PresenceState::Away;
+        entry.state = PresenceState::Online;
+        let persist =
+            now_instant.duration_since(entry.last_persisted) >= LAST_SEEN_PERSIST_INTERVAL;
+        if persist {
+            entry.last_persisted = now_instant;
+        }
+        (came_back && entry.manual.is_none(), persist)
+    };
+
+    let last_seen = now();
+    if persist {
+        app_state.set_last_seen(user_id, last_seen).await;
+    }
+    if came_back {
+        notify_peers(app_state, user_id, PresenceState::Online, last_seen).await;
+    }
+}
+
+pub async fn set(
+    app_state: &AppState,
+    user_id: &str,
+    state: PresenceState,
+    tx: &UnboundedSender<Message>,
+) {
+    let changed = {
+        let mut presence = app_state.presence.lock().await;
+        let Some(entry) = presence.get_mut(user_id) else {
+            return;
+        };
+        let before = entry.effective();
+        entry.manual = match state {
+            PresenceState::Online => None,
+            state => Some(state),
+        };
+        let after = entry.effective();
+        (after != before).then_some(after)
+    };
+
+    if let Err(e) = tx.send(Message::Text(
+        serde_json::json!({
+            "type":"presence_set",
+            "state":state
+        })
+        .to_string()
+        .into(),
+    )) {
+        error!("Error while sending presence: {:?}", e);
+    }
+    // Peers get the resulting state: setting online while idle stays idle.
+    if let Some(effective) = changed {
+        info!("User {} set presence to {:?}", user_id, effective);
+        notify_peers(app_state, user_id, effective, now()).await;
+    }
+}
+
+pub async fn get(app_state: &AppState, users: Vec<String>, tx: &UnboundedSender<Message>) {
+    if users.len() > MAX_USERS_PER_REQUEST {
+        send_error(tx, "too_many_users", "Too many users requested");
+        return;
+    }
+    let online: HashMap<String, PresenceState> = {
+        let presence = app_state.presence.lock().await;
+        users
+            .iter()
+            .filter_map(|u| presence.get(u).map(|entry| (u.clone(), entry.effective())))
+            .collect()
+    };
+
+    let mut result = serde_json::Map::new();
+    for user in users.iter() {
+        let state = online.get(user).copied().unwrap_or(PresenceState::Offline);
+        let last_seen = if state == PresenceState::Offline {
+            app_state.get_last_seen(user).await
+        } else {
+            Some(now())
+        };
+        result.insert(
+            user.clone(),
+            serde_json::json!({
+                "state":state,
+                "last_seen":last_seen
+            }),
+        );
+    }
+
+    if let Err(e) = tx.send(Message::Text(
+        serde_json::json!({
+            "type":"presence_list",
+            "presence":result
+        })
+        .to_string()
+        .into(),
+    )) {
+        error!("Error while sending presence list: {:?}", e);
+    }
+}
+
+pub async fn idle_watcher(app_state: AppState) {
+    let mut interval = tokio::time::interval(IDLE_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let went_away: Vec<String> = {
+            let mut presence = app_state.presence.lock().await;
+            presence
+                .iter_mut()
+                .filter(|(_, entry)| {
+                    entry.state == PresenceState::Online
+                        && entry.last_active.elapsed() >= AWAY_AFTER
+                })
+                .map(|(user, entry)| {
+                    entry.state = PresenceState::Away;
+                    (user.clone(), entry.manual.is_none())
+                })
+                .filter(|(_, visible)| *visible)
+                .map(|(user, _)| user)
+                .collect()
+        };
+        for user in went_away {
+            let last_seen = app_state.get_last_seen(&user).await.unwrap_or_else(now);
+            notify_peers(&app_state, &user, PresenceState::Away, last_seen).await;
+        }
+    }
+}
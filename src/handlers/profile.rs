@@ -0,0 +1,120 @@
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{error, info};
+
+use crate::{
+    app_state::AppState,
+    blobs::BlobStore,
+    handlers::{room::send_to_users, send_error},
+    http,
+    utils::now,
+};
+
+const MAX_DISPLAY_NAME_LEN: usize = 64;
+const MAX_AVATAR_LEN: usize = 512;
+const MAX_STATUS_LEN: usize = 140;
+const MAX_PROFILES_PER_REQUEST: usize = 100;
+
+// An avatar is either an http(s) URL or the id of an uploaded blob.
+fn valid_avatar(avatar: &str) -> bool {
+    if avatar.starts_with("https://") || avatar.starts_with("http://") {
+        return !avatar.contains(char::is_whitespace);
+    }
+    BlobStore::is_blob_id(avatar)
+}
+
+// Empty strings clear a field, missing fields are left as they are.
+fn normalize(value: Option<String>) -> Option<Option<String>> {
+    value.map(|v| {
+        let v = v.trim().to_owned();
+        (!v.is_empty()).then_some(v)
+    })
+}
+
+pub async fn set(
+    app_state: &AppState,
+    user_id: &String,
+    display_name: Option<String>,
+    avatar: Option<String>,
+    status: Option<String>,
+    tx: &UnboundedSender<Message>,
+) {
+    let display_name = normalize(display_name);
+    let avatar = normalize(avatar);
+    let status = normalize(status);
+
+    if let Some(Some(name)) = &display_name
+        && name.chars().count() > MAX_DISPLAY_NAME_LEN
+    {
+        send_error(tx, "invalid_profile", "Display name is too long");
+        return;
+    }
+    if let Some(Some(avatar)) = &avatar
+        && (avatar.len() > MAX_AVATAR_LEN || !valid_avatar(avatar))
+    {
+        send_error(tx, "invalid_profile", "Avatar must be a URL or a blob id");
+        return;
+    }
+    // Setting an avatar makes the blob visible to everyone, so it has to be
+    // an image the user can already see.
+    if let Some(Some(avatar)) = &avatar
+        && BlobStore::is_blob_id(avatar)
+        && !http::find_file(app_state, user_id, avatar)
+            .await
+            .is_some_and(|(mime, _)| mime.starts_with("image/"))
+    {
+        send_error(
+            tx,
+            "invalid_profile",
+            "Avatar must be an image you have access to",
+        );
+        return;
+    }
+    if let Some(Some(status)) = &status
+        && status.chars().count() > MAX_STATUS_LEN
+    {
+        send_error(tx, "invalid_profile", "Status is too long");
+        return;
+    }
+
+    let mut profile = app_state.get_profile(user_id).await;
+    if let Some(display_name) = display_name {
+        profile.display_name = display_name;
+    }
+    if let Some(avatar) = avatar {
+        profile.avatar = avatar;
+    }
+    if let Some(status) = status {
+        profile.status = status;
+    }
+    profile.updated_at = now();
+    app_state.set_profile(&profile).await;
+    info!("User {} updated their profile", user_id);
+
+    let mut event = serde_json::to_value(&profile).unwrap();
+    event["type"] = "profile_updated".into();
+    let event = event.to_string();
+    if let Err(e) = tx.send(Message::Text(event.clone().into())) {
+        error!("Error while sending profile: {:?}", e);
+    }
+    let peers = app_state.room_peers(user_id).await;
+    send_to_users(app_state, &peers, event).await;
+}
+
+pub async fn get(app_state: &AppState, users: Vec<String>, tx: &UnboundedSender<Message>) {
+    if users.len() > MAX_PROFILES_PER_REQUEST {
+        send_error(tx, "too_many_users", "Too many users requested");
+        return;
+    }
+    let profiles = app_state.get_profiles(&users).await;
+    if let Err(e) = tx.send(Message::Text(
+        serde_json::json!({
+            "type":"profiles",
+            "profiles":profiles
+        })
+        .to_string()
+        .into(),
+    )) {
+        error!("Error while sending profiles: {:?}", e);
+    }
+}
@@ -0,0 +1,99 @@
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::error;
+
+use crate::{
+    app_state::AppState,
+    handlers::{
+        room::{broadcast_to_others, broadcast_to_room},
+        send_error,
+    },
+};
+
+pub async fn mark_read(
+    app_state: &AppState,
+    user_id: &String,
+    room: String,
+    up_to_seq: u64,
+    tx: &UnboundedSender<Message>,
+) {
+    let Some(r) = app_state.find_room(&room).await else {
+        send_error(tx, "not_found", "Room not found");
+        return;
+    };
+    if !r.users.contains(user_id) {
+        send_error(tx, "forbidden", "Not a member of this room");
+        return;
+    }
+
+    let Some(r) = app_state
+        .mark_read(room.clone(), user_id.clone(), up_to_seq)
+        .await
+    else {
+        return;
+    };
+    let up_to_seq = r.read_positions.get(user_id).copied().unwrap_or(0);
+
+    if let Err(e) = tx.send(Message::Text(
+        serde_json::json!({
+            "type":"read_marked",
+            "room":room,
+            "up_to_seq":up_to_seq,
+            "unread":r.unread_count(user_id)
+        })
+        .to_string()
+        .into(),
+    )) {
+        error!("Error while sending read position for {}: {:?}", room, e);
+    }
+
+    if r.read_receipts {
+        broadcast_to_others(
+            app_state,
+            &r,
+            user_id,
+            serde_json::json!({
+                "type":"read_receipt",
+                "room":room,
+                "user":user_id,
+                "up_to_seq":up_to_seq
+            })
+            .to_string(),
+        )
+        .await;
+    }
+}
+
+pub async fn set_read_receipts(
+    app_state: &AppState,
+    user_id: &str,
+    room: String,
+    enabled: bool,
+    tx: &UnboundedSender<Message>,
+) {
+    let Some(r) = app_state.find_room(&room).await else {
+        send_error(tx, "not_found", "Room not found");
+        return;
+    };
+    if r.admin != user_id {
+        send_error(
+            tx,
+            "forbidden",
+            "Only the room admin can change read receipts",
+        );
+        return;
+    }
+
+    app_state.set_read_receipts(room.clone(), enabled).await;
+    broadcast_to_room(
+        app_state,
+        &r,
+        serde_json::json!({
+            "type":"read_receipts_changed",
+            "room":room,
+            "enabled":enabled
+        })
+        .to_string(),
+    )
+    .await;
+}
@@ -0,0 +1,131 @@
+use std::collections::HashSet;
+
+use tokio::{fs, io::AsyncWriteExt};
+use tracing::{error, info};
+
+use crate::{
+    app_state::AppState,
+    handlers::room::broadcast_to_room,
+    types::{Room, RoomMessage},
+    utils::now,
+};
+
+// Seq of the newest message the room's retention lets go, if any.
+fn prune_through(app_state: &AppState, room: &Room) -> Option<u64> {
+    let retention = room.retention.unwrap_or(app_state.config.message_retention);
+    let over_count = retention
+        .max_count
+        .map_or(0, |max| room.messages.len().saturating_sub(max));
+    let cutoff = retention.max_age_hours.map_or(0, |hours| {
+        now().saturating_sub(hours.saturating_mul(60 * 60 * 1000))
+    });
+    let too_old = room
+        .messages
+        .iter()
+        .take_while(|m| m.sent_at < cutoff)
+        .count();
+    let prune = over_count.max(too_old);
+    prune
+        .checked_sub(1)
+        .and_then(|last| room.messages.get(last))
+        .map(|m| m.seq)
+}
+
+// Messages to drop: everything through `through_seq`, plus the replies of
+// every thread whose root goes, so no reply outlives its root. Replies whose
+// root is already gone, e.g. because they came in while it was being pruned,
+// are swept up here too.
+fn pruned(room: &Room, through_seq: Option<u64>) -> Vec<RoomMessage> {
+    let pruned = |m: &RoomMessage| through_seq.is_some_and(|seq| m.seq <= seq);
+    let roots: HashSet<&str> = room
+        .messages
+        .iter()
+        .filter(|m| m.thread_root.is_none() && !pruned(m))
+        .map(|m| m.id.as_str())
+        .collect();
+    room.messages
+        .iter()
+        .filter(|m| {
+            pruned(m)
+                || m.thread_root
+                    .as_ref()
+                    .is_some_and(|root| !roots.contains(root.as_str()))
+        })
+        .cloned()
+        .collect()
+}
+
+// Appends messages to the room's file in the cold storage directory, one
+// JSON object per line.
+async fn archive(app_state: &AppState, room: &str, messages: &[RoomMessage]) -> bool {
+    let Some(dir) = &app_state.config.cold_storage_dir else {
+        return true;
+    };
+    let mut lines = String::new();
+    for message in messages {
+        match serde_json::to_string(message) {
+            Ok(line) => {
+                lines.push_str(&line);
+                lines.push('\n');
+            }
+            Err(e) => {
+                error!("Error while serializing message {}: {:?}", message.id, e);
+                return false;
+            }
+        }
+    }
+    let result = async {
+        fs::create_dir_all(dir).await?;
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(format!("{}.jsonl", room)))
+            .await?;
+        file.write_all(lines.as_bytes()).await?;
+        file.flush().await
+    }
+    .await;
+    if let Err(e) = result {
+        error!("Error while archiving messages of {}: {:?}", room, e);
+        return false;
+    }
+    true
+}
+
+// Periodically drops messages that fall outside each room's retention. When
+// cold storage is configured the messages are written there first, and a
+// room is left alone if that fails.
+pub async fn pruner(app_state: AppState) {
+    let mut interval = tokio::time::interval(app_state.config.retention_interval);
+    loop {
+        interval.tick().await;
+        for room in app_state.get_rooms().await {
+            let through_seq = prune_through(&app_state, &room);
+            let pruned = pruned(&room, through_seq);
+            if pruned.is_empty() || !archive(&app_state, &room.room, &pruned).await {
+                continue;
+            }
+            let ids = pruned.iter().map(|m| m.id.as_str()).collect();
+            let history_start = through_seq.map_or(0, |seq| seq + 1);
+            let Some(r) = app_state
+                .prune_messages(&room.room, &ids, history_start)
+                .await
+            else {
+                continue;
+            };
+            info!("Pruned {} messages from room {}", pruned.len(), r.room);
+            broadcast_to_room(
+                &app_state,
+                &r,
+                serde_json::json!({
+                    "type":"history_pruned",
+                    "room":r.room,
+                    "history_start":r.history_start,
+                    "pruned":pruned.len()
+                })
+                .to_string(),
+            )
+            .await;
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use tokio::sync::mpsc::UnboundedSender;
 use tokio_tungstenite::tungstenite::Message;
 use tracing::{error, info, warn};
@@ -5,42 +7,155 @@ use uuid::Uuid;
 
 use crate::{
     app_state::AppState,
-    types::{Room, RoomMessage},
+    encoding::Encoded,
+    handlers::{self, send_error, send_to_devices},
+    rich_text,
+    types::{Attachment, MessageRetention, Room, RoomLifecycle, RoomMessage, RoomUpdate},
+    utils::now,
 };
 
+const MAX_ROOM_NAME_LEN: usize = 100;
+const MAX_TOPIC_LEN: usize = 250;
+const MAX_DESCRIPTION_LEN: usize = 2000;
+const MAX_TAGS: usize = 10;
+const MAX_TAG_LEN: usize = 32;
+const MAX_ATTRIBUTES_SIZE: usize = 8 * 1024;
+const MAX_TTL_HOURS: u64 = 24 * 365;
+const MAX_RETENTION_HOURS: u64 = 24 * 365 * 10;
+
+fn valid_lifecycle(lifecycle: &RoomLifecycle, tx: &UnboundedSender<Message>) -> bool {
+    if let RoomLifecycle::Ttl { hours } = lifecycle
+        && !(1..=MAX_TTL_HOURS).contains(hours)
+    {
+        send_error(
+            tx,
+            "invalid_lifecycle",
+            "TTL must be between 1 hour and a year",
+        );
+        return false;
+    }
+    true
+}
+
+fn valid_retention(retention: &MessageRetention, tx: &UnboundedSender<Message>) -> bool {
+    let valid = retention.max_count.is_none_or(|count| count >= 1)
+        && retention
+            .max_age_hours
+            .is_none_or(|hours| (1..=MAX_RETENTION_HOURS).contains(&hours));
+    if !valid {
+        send_error(
+            tx,
+            "invalid_retention",
+            "max_count must be at least 1 and max_age_hours between 1 and 87600",
+        );
+    }
+    valid
+}
+
+pub fn ensure_writable(room: &Room, tx: &UnboundedSender<Message>) -> bool {
+    if room.is_archived() {
+        send_error(tx, "room_archived", "Room is archived and read-only");
+        return false;
+    }
+    true
+}
+
 pub async fn join(
     app_state: &AppState,
     user_id: &String,
     room: &String,
     tx: &UnboundedSender<Message>,
 ) {
-    let room_data = app_state
+    let Some(existing) = app_state.find_room(room).await else {
+        send_error(tx, "not_found", "Room not found");
+        return;
+    };
+    if !ensure_writable(&existing, tx) {
+        return;
+    }
+    let already_member = existing.users.contains(user_id);
+    if !already_member && existing.users.len() >= app_state.config.max_room_size {
+        send_error(tx, "room_full", "Room is full");
+        return;
+    }
+    let Some(room_data) = app_state
         .add_to_room(room.to_owned(), user_id.to_owned())
-        .await;
+        .await
+    else {
+        send_error(tx, "not_found", "Room not found");
+        return;
+    };
     let room_id = room_data.room.clone();
     let response = serde_json::json!({
         "type": "room_joined",
         "room_id": room_id,
-        "room_name": room_data.room_name
+        "room_name": room_data.room_name,
+        "version": room_data.version
     });
 
     if let Err(e) = tx.send(Message::Text(response.to_string().into())) {
         error!("Error while sending room_id {}: {:?}", room_id, e);
     }
+
+    if !already_member {
+        handlers::room_list::refresh(app_state, room).await;
+        broadcast_to_others(
+            app_state,
+            &room_data,
+            user_id,
+            serde_json::json!({
+                "type":"member_joined",
+                "room":room_id,
+                "user":user_id,
+                "version":room_data.version
+            })
+            .to_string(),
+        )
+        .await;
+    }
 }
+// Expects the name already trimmed.
+fn valid_room_name(room_name: &str, tx: &UnboundedSender<Message>) -> bool {
+    if room_name.is_empty() || room_name.chars().count() > MAX_ROOM_NAME_LEN {
+        send_error(tx, "invalid_room_name", "Room name is empty or too long");
+        return false;
+    }
+    true
+}
+
 pub async fn create(
     app_state: &AppState,
     user_id: &String,
-    room_name: &String,
+    room_name: &str,
+    lifecycle: RoomLifecycle,
     tx: &UnboundedSender<Message>,
 ) {
+    let room_name = room_name.trim();
+    if !valid_room_name(room_name, tx) || !valid_lifecycle(&lifecycle, tx) {
+        return;
+    }
     let room_id = Uuid::new_v4().to_string();
     let room = Room {
-        room_name: room_name.clone(),
+        room_name: room_name.to_owned(),
         room: room_id.clone(),
         messages: vec![],
         users: vec![user_id.clone()],
         admin: user_id.clone(),
+        last_seq: 0,
+        read_receipts: false,
+        read_positions: HashMap::new(),
+        version: 0,
+        topic: String::new(),
+        description: String::new(),
+        tags: vec![],
+        created_at: now(),
+        last_activity_at: now(),
+        attributes: serde_json::Map::new(),
+        lifecycle,
+        archived_at: None,
+        former_users: vec![],
+        retention: None,
+        history_start: 0,
     };
     app_state.create_room(room).await;
 
@@ -62,113 +177,621 @@ pub async fn create(
         "room_name": room_name
     });
 
-    let connections_guard = app_state.connections.lock().await;
-    for (id, client_tx) in connections_guard.iter() {
-        if *id != user_id.clone() {
-            let _ = client_tx.send(Message::Text(broadcast_msg.to_string().into()));
+    // Connections with a room list subscription get the room as a list
+    // update instead, and only if it falls inside their view.
+    let broadcast_msg = Encoded::new(Message::Text(broadcast_msg.to_string().into()));
+    {
+        let connections_guard = app_state.connections.lock().await;
+        let room_lists = app_state.room_lists.lock().await;
+        for (id, devices) in connections_guard.iter() {
+            if *id != user_id.clone() {
+                for (connection, device) in devices {
+                    if !room_lists.contains_key(connection) {
+                        let _ = device.tx.send(broadcast_msg.get(device.encoding));
+                    }
+                }
+            }
         }
     }
+    handlers::room_list::refresh(app_state, &room_id).await;
 
     info!("Room created: {} ({})", room_name, room_id);
 }
 
-pub async fn broadcast_to_all(app_state: &AppState, message: String) {
+// Room news for its members and for connections without a room list
+// subscription. Subscribed connections learn about the room from their list
+// view instead, so they aren't told about rooms outside of it.
+pub async fn broadcast_to_unsubscribed(app_state: &AppState, room: &Room, message: String) {
+    let message = Encoded::new(Message::Text(message.into()));
     let connections_guard = app_state.connections.lock().await;
-    for conn in connections_guard.iter() {
-        let (_, tx) = conn;
-        if let Err(err) = tx.send(Message::Text(message.clone().into())) {
-            error!("Error while sending message {}", err.to_string());
+    let room_lists = app_state.room_lists.lock().await;
+    for (id, devices) in connections_guard.iter() {
+        let member = room.users.contains(id);
+        for (connection, device) in devices {
+            if member || !room_lists.contains_key(connection) {
+                let _ = device.tx.send(message.get(device.encoding));
+            }
         }
     }
 }
 
-pub async fn get(app_state: &AppState, tx: &UnboundedSender<Message>) {
-    let mut _rooms: Vec<Room> = app_state.get_rooms().await;
-    let message = serde_json::to_string(&_rooms.clone()).unwrap();
+pub async fn send_to_users(app_state: &AppState, users: &[String], message: String) {
+    let message = Encoded::new(Message::Text(message.into()));
+    let connections_guard = app_state.connections.lock().await;
+    for user in users {
+        send_to_devices(connections_guard.get(user), &message);
+    }
+}
+
+pub async fn get(app_state: &AppState, user_id: &str, version: u32, tx: &UnboundedSender<Message>) {
+    let _rooms: Vec<Room> = app_state.get_rooms().await;
+    let rooms: Vec<serde_json::Value> = _rooms
+        .iter()
+        .filter(|room| !room.is_archived())
+        .map(|room| {
+            let mut value = serde_json::to_value(room.public()).unwrap();
+            if room.users.iter().any(|u| u == user_id) {
+                value["unread"] = room.unread_count(user_id).into();
+            }
+            value
+        })
+        .collect();
+    // v1 clients expect a bare array
+    let message = if version < 2 {
+        serde_json::to_string(&rooms).unwrap()
+    } else {
+        serde_json::json!({
+            "type":"rooms",
+            "rooms":rooms
+        })
+        .to_string()
+    };
     tx.send(Message::Text(message.into())).unwrap();
 }
 
-pub async fn broadcast_message(app_state: &AppState, message: String, room: String, by: String) {
+pub async fn broadcast_to_room(app_state: &AppState, room: &Room, message: String) {
+    let message = Encoded::new(Message::Text(message.into()));
     let connections_guard = app_state.connections.lock().await;
-    let _room = app_state.get_room(room.clone()).await;
-    let room_message = RoomMessage {
-        by: by.clone(),
-        message: message.clone(),
-    };
-    app_state.add_message(room.clone(), room_message).await;
-
-    for user in _room.users.iter() {
-        if let Some(tx) = connections_guard.get(user) {
-            if let Err(err) = tx.send(Message::Text(
-                serde_json::json!({
-                    "type":"room_broadcast",
-                    "by":by,
-                    "message":message
-                })
-                .to_string()
-                .into(),
-            )) {
-                error!("Error while sending message to {user:?}: {err:?}")
-            }
-        } else {
+    for user in room.users.iter() {
+        if !send_to_devices(connections_guard.get(user), &message) {
             warn!("User {user:?} not found in connections");
         }
     }
 }
 
-pub async fn list_messages(app_state: &AppState, room: &String, tx: &UnboundedSender<Message>) {
-    info!("list room request received");
-    let _room = app_state.get_room(room.to_owned()).await;
-    let _list = _room.messages;
-    let _ = tx
-        .send(Message::Text(
+pub async fn broadcast_to_others(app_state: &AppState, room: &Room, except: &str, message: String) {
+    let message = Encoded::new(Message::Text(message.into()));
+    let connections_guard = app_state.connections.lock().await;
+    for user in room.users.iter().filter(|u| *u != except) {
+        send_to_devices(connections_guard.get(user), &message);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn broadcast_message(
+    app_state: &AppState,
+    message: String,
+    content: Option<String>,
+    room: String,
+    by: String,
+    reply_to: Option<String>,
+    attachment: Option<Attachment>,
+    tx: &UnboundedSender<Message>,
+) {
+    let max_size = app_state.config.max_message_size;
+    if message.len() > max_size || content.as_ref().is_some_and(|c| c.len() > max_size) {
+        send_error(tx, "message_too_large", "Message is too large");
+        return;
+    }
+    if content.is_none() && message.trim().is_empty() && attachment.is_none() {
+        send_error(tx, "empty_message", "Message is empty");
+        return;
+    }
+    let Some(_room) = app_state.find_room(&room).await else {
+        send_error(tx, "not_found", "Room not found");
+        return;
+    };
+    if !ensure_writable(&_room, tx) {
+        return;
+    }
+    let content = match content
+        .map(|c| rich_text::parse(&c, &_room.users))
+        .transpose()
+    {
+        Ok(content) => content,
+        Err(reason) => {
+            send_error(tx, "invalid_content", reason);
+            return;
+        }
+    };
+    let message = content.as_deref().map_or(message, rich_text::plain_text);
+    let mut room_message = RoomMessage::new(by.clone(), message.clone());
+    room_message.attachment = attachment;
+    if let Some(parent_id) = reply_to {
+        let Some(parent) = _room.messages.iter().find(|m| m.id == parent_id) else {
+            send_error(tx, "not_found", "Message not found");
+            return;
+        };
+        room_message.thread_root = Some(parent.thread_root.clone().unwrap_or(parent.id.clone()));
+        room_message.reply_to = Some(parent_id);
+    }
+    let (mentions, mentions_room) = match &content {
+        Some(blocks) => rich_text::mentions(blocks),
+        None => handlers::mentions::parse(&message, &_room.users),
+    };
+    room_message.content = content;
+    room_message.mentions = mentions;
+    room_message.mentions_room = mentions_room;
+    let Some(room_message) = app_state.add_message(room.clone(), room_message).await else {
+        send_error(tx, "not_found", "Room not found");
+        return;
+    };
+    handlers::typing::stop(app_state, &_room, &by).await;
+    handlers::room_list::refresh(app_state, &room).await;
+
+    broadcast_to_room(
+        app_state,
+        &_room,
+        serde_json::json!({
+            "type":"room_broadcast",
+            "id":room_message.id,
+            "seq":room_message.seq,
+            "room":room,
+            "by":by,
+            "message":message,
+            "content":room_message.content,
+            "sent_at":room_message.sent_at,
+            "reply_to":room_message.reply_to,
+            "thread_root":room_message.thread_root,
+            "mentions":room_message.mentions,
+            "mentions_room":room_message.mentions_room,
+            "attachment":room_message.attachment
+        })
+        .to_string(),
+    )
+    .await;
+    handlers::mentions::notify(app_state, &_room, &room_message).await;
+
+    if let Some(root) = &room_message.thread_root {
+        let reply_count = _room
+            .messages
+            .iter()
+            .filter(|m| m.thread_root.as_ref() == Some(root))
+            .count()
+            + 1;
+        broadcast_to_room(
+            app_state,
+            &_room,
             serde_json::json!({
-                "type":"list_messages",
-                "messages": _list.to_owned(),
+                "type":"thread_updated",
+                "room":room,
+                "root":root,
+                "reply_count":reply_count,
+                "last_reply_at":room_message.sent_at
             })
-            .to_string()
-            .into(),
-        ))
-        .unwrap();
+            .to_string(),
+        )
+        .await;
+    }
 }
 
-pub async fn details(app_state: &AppState, tx: &UnboundedSender<Message>, room: &String) {
-    let _room = app_state.get_room(room.to_owned()).await;
-    tx.send(Message::Text(serde_json::to_string(&_room).unwrap().into()))
-        .unwrap();
+pub async fn list_messages(
+    app_state: &AppState,
+    user_id: &str,
+    room: &str,
+    tx: &UnboundedSender<Message>,
+) {
+    info!("list room request received");
+    let Some(_room) = app_state.find_room(room).await else {
+        send_error(tx, "not_found", "Room not found");
+        return;
+    };
+    if !_room.can_read(user_id) {
+        send_error(tx, "forbidden", "Not a member of this room");
+        return;
+    }
+    let _list: Vec<RoomMessage> = _room
+        .messages
+        .iter()
+        .filter(|m| m.thread_root.is_none())
+        .map(|m| m.redacted())
+        .collect();
+    let authors: Vec<String> = _list.iter().map(|m| m.by.clone()).collect();
+    let profiles = app_state.get_profiles(&authors).await;
+    if let Err(e) = tx.send(Message::Text(
+        serde_json::json!({
+            "type":"list_messages",
+            "messages": _list.to_owned(),
+            "history_start": _room.history_start,
+            "profiles": profiles,
+        })
+        .to_string()
+        .into(),
+    )) {
+        error!("Error while sending messages: {:?}", e);
+    }
 }
 
-pub async fn leave_room(
+pub async fn details(
     app_state: &AppState,
+    user_id: &str,
     tx: &UnboundedSender<Message>,
+    room: &str,
+    version: u32,
+) {
+    let Some(_room) = app_state.find_room(room).await else {
+        send_error(tx, "not_found", "Room not found");
+        return;
+    };
+    if !_room.can_read(user_id) {
+        send_error(tx, "forbidden", "Not a member of this room");
+        return;
+    }
+    let _room = _room.public();
+    let mut users = _room.users.clone();
+    users.push(_room.admin.clone());
+    users.extend(_room.messages.iter().map(|m| m.by.clone()));
+    let profiles = app_state.get_profiles(&users).await;
+    // v1 clients expect the room itself with profiles mixed in
+    let response = if version < 2 {
+        let mut response = serde_json::to_value(&_room).unwrap();
+        response["profiles"] = serde_json::to_value(profiles).unwrap();
+        response
+    } else {
+        serde_json::json!({
+            "type":"room_details",
+            "room":_room.state(),
+            "profiles":profiles
+        })
+    };
+    tx.send(Message::Text(response.to_string().into())).unwrap();
+}
+
+pub async fn sync(app_state: &AppState, user_id: &str, room: &str, tx: &UnboundedSender<Message>) {
+    let Some(r) = app_state.find_room(room).await else {
+        send_error(tx, "not_found", "Room not found");
+        return;
+    };
+    if !r.can_read(user_id) {
+        send_error(tx, "forbidden", "Not a member of this room");
+        return;
+    }
+    if let Err(e) = tx.send(Message::Text(
+        serde_json::json!({
+            "type":"room_state",
+            "room":r.state()
+        })
+        .to_string()
+        .into(),
+    )) {
+        error!("Error while sending room state {}: {:?}", room, e);
+    }
+}
+
+pub async fn rename(
+    app_state: &AppState,
+    user_id: &str,
     room: String,
-    user: String,
+    room_name: String,
+    tx: &UnboundedSender<Message>,
 ) {
-    let room = app_state.remove_from_room(room, user).await;
-    match room {
-        Some(r) => {
-            if let Err(err) = tx.send(Message::Text(
-                serde_json::json!({
-                    "type":"room_left",
-                    "room":r.room
-                })
-                .to_string()
-                .into(),
-            )) {
-                error!("Error while sending message {}", err.to_string());
+    let room_name = room_name.trim().to_owned();
+    if !valid_room_name(&room_name, tx) {
+        return;
+    }
+    let Some(r) = app_state.find_room(&room).await else {
+        send_error(tx, "not_found", "Room not found");
+        return;
+    };
+    if !r.is_moderator(user_id) {
+        send_error(tx, "forbidden", "Only moderators can rename the room");
+        return;
+    }
+    if !ensure_writable(&r, tx) {
+        return;
+    }
+
+    let Some(r) = app_state.rename_room(room, room_name).await else {
+        send_error(tx, "not_found", "Room not found");
+        return;
+    };
+    handlers::room_list::refresh(app_state, &r.room).await;
+    info!(
+        "User {} renamed room {} to {}",
+        user_id, r.room, r.room_name
+    );
+    broadcast_to_unsubscribed(
+        app_state,
+        &r,
+        serde_json::json!({
+            "type":"room_renamed",
+            "room":r.room,
+            "room_name":r.room_name,
+            "by":user_id,
+            "version":r.version
+        })
+        .to_string(),
+    )
+    .await;
+}
+
+pub async fn set_archived(
+    app_state: &AppState,
+    user_id: &str,
+    room: String,
+    archived: bool,
+    tx: &UnboundedSender<Message>,
+) {
+    let Some(r) = app_state.find_room(&room).await else {
+        send_error(tx, "not_found", "Room not found");
+        return;
+    };
+    if !r.is_moderator(user_id) {
+        send_error(tx, "forbidden", "Only moderators can archive the room");
+        return;
+    }
+    if r.is_archived() == archived {
+        return;
+    }
+
+    let updated = app_state
+        .update_room(&room, |r| r.archived_at = archived.then(now))
+        .await;
+    if let Some(r) = updated {
+        info!(
+            "User {} {} room {}",
+            user_id,
+            if archived { "archived" } else { "unarchived" },
+            room
+        );
+        handlers::room_list::refresh(app_state, &room).await;
+        broadcast_to_unsubscribed(
+            app_state,
+            &r,
+            serde_json::json!({
+                "type":if archived { "room_archived" } else { "room_unarchived" },
+                "room":room,
+                "room_name":r.room_name,
+                "by":user_id,
+                "version":r.version
+            })
+            .to_string(),
+        )
+        .await;
+    }
+}
+
+pub async fn list_archived(app_state: &AppState, user_id: &str, tx: &UnboundedSender<Message>) {
+    let rooms: Vec<serde_json::Value> = app_state
+        .get_rooms()
+        .await
+        .iter()
+        .filter(|r| r.is_archived() && r.can_read(user_id))
+        .map(|r| r.state())
+        .collect();
+    if let Err(e) = tx.send(Message::Text(
+        serde_json::json!({
+            "type":"archived_rooms",
+            "rooms":rooms
+        })
+        .to_string()
+        .into(),
+    )) {
+        error!("Error while sending archived rooms: {:?}", e);
+    }
+}
+
+fn normalize_tags(tags: Vec<String>) -> Option<Vec<String>> {
+    let mut normalized: Vec<String> = Vec::new();
+    for tag in tags {
+        let tag = tag.trim().to_lowercase();
+        if tag.is_empty() || tag.chars().count() > MAX_TAG_LEN {
+            return None;
+        }
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    (normalized.len() <= MAX_TAGS).then_some(normalized)
+}
+
+pub async fn update(
+    app_state: &AppState,
+    user_id: &str,
+    room: String,
+    update: RoomUpdate,
+    tx: &UnboundedSender<Message>,
+) {
+    let RoomUpdate {
+        topic,
+        description,
+        tags,
+        attributes,
+        lifecycle,
+        retention,
+    } = update;
+    let topic = topic.map(|topic| topic.trim().to_owned());
+    if topic
+        .as_ref()
+        .is_some_and(|t| t.chars().count() > MAX_TOPIC_LEN)
+    {
+        send_error(tx, "invalid_topic", "Topic is too long");
+        return;
+    }
+    if description
+        .as_ref()
+        .is_some_and(|d| d.chars().count() > MAX_DESCRIPTION_LEN)
+    {
+        send_error(tx, "invalid_description", "Description is too long");
+        return;
+    }
+    let tags = match tags.map(normalize_tags) {
+        Some(None) => {
+            send_error(
+                tx,
+                "invalid_tags",
+                "Too many tags, or a tag is empty or too long",
+            );
+            return;
+        }
+        Some(Some(tags)) => Some(tags),
+        None => None,
+    };
+    if lifecycle.is_some_and(|lifecycle| !valid_lifecycle(&lifecycle, tx)) {
+        return;
+    }
+    if retention
+        .flatten()
+        .is_some_and(|retention| !valid_retention(&retention, tx))
+    {
+        return;
+    }
+    let Some(r) = app_state.find_room(&room).await else {
+        send_error(tx, "not_found", "Room not found");
+        return;
+    };
+    if !r.is_moderator(user_id) {
+        send_error(tx, "forbidden", "Only moderators can update the room");
+        return;
+    }
+    if !ensure_writable(&r, tx) {
+        return;
+    }
+
+    // Attributes are merged; a null value removes the key.
+    let mut merged = r.attributes.clone();
+    if let Some(attributes) = &attributes {
+        for (key, value) in attributes {
+            if value.is_null() {
+                merged.remove(key);
+            } else {
+                merged.insert(key.clone(), value.clone());
             }
         }
-        None => {
-            let rooms = app_state.get_rooms().await;
-            broadcast_to_all(
-                app_state,
-                serde_json::json!({
-                    "type":"rooms_available",
-                    "rooms":rooms
-                })
-                .to_string(),
-            )
-            .await;
+        if serde_json::to_string(&merged).map_or(0, |a| a.len()) > MAX_ATTRIBUTES_SIZE {
+            send_error(tx, "invalid_attributes", "Attributes are too large");
+            return;
         }
     }
+
+    let mut changes = serde_json::Map::new();
+    let updated = app_state
+        .update_room(&room, |r| {
+            if let Some(topic) = topic {
+                changes.insert("topic".into(), topic.clone().into());
+                r.topic = topic;
+            }
+            if let Some(description) = description {
+                changes.insert("description".into(), description.clone().into());
+                r.description = description;
+            }
+            if let Some(tags) = tags {
+                changes.insert("tags".into(), tags.clone().into());
+                r.tags = tags;
+            }
+            if attributes.is_some() {
+                changes.insert("attributes".into(), merged.clone().into());
+                r.attributes = merged;
+            }
+            if let Some(lifecycle) = lifecycle {
+                changes.insert(
+                    "lifecycle".into(),
+                    serde_json::to_value(lifecycle).unwrap_or_default(),
+                );
+                r.lifecycle = lifecycle;
+            }
+            if let Some(retention) = retention {
+                changes.insert(
+                    "retention".into(),
+                    serde_json::to_value(retention).unwrap_or_default(),
+                );
+                r.retention = retention;
+            }
+        })
+        .await;
+
+    if let Some(r) = updated {
+        info!("User {} updated room {}", user_id, room);
+        handlers::room_list::refresh(app_state, &room).await;
+        broadcast_to_room(
+            app_state,
+            &r,
+            serde_json::json!({
+                "type":"room_updated",
+                "room":room,
+                "changes":changes,
+                "by":user_id,
+                "version":r.version
+            })
+            .to_string(),
+        )
+        .await;
+    }
+}
+
+// Removes a member and tells the remaining members what changed.
+async fn remove_member(app_state: &AppState, room: &str, user: &str) -> Option<Room> {
+    let before = app_state.find_room(room).await?;
+    let r = app_state
+        .remove_from_room(room.to_owned(), user.to_owned())
+        .await?;
+    if r.version == before.version {
+        return Some(r);
+    }
+    handlers::room_list::refresh(app_state, room).await;
+
+    broadcast_to_room(
+        app_state,
+        &r,
+        serde_json::json!({
+            "type":"member_left",
+            "room":room,
+            "user":user,
+            "version":before.version + 1
+        })
+        .to_string(),
+    )
+    .await;
+    if r.admin != before.admin {
+        broadcast_to_room(
+            app_state,
+            &r,
+            serde_json::json!({
+                "type":"admin_changed",
+                "room":room,
+                "admin":r.admin,
+                "version":r.version
+            })
+            .to_string(),
+        )
+        .await;
+    }
+    Some(r)
+}
+
+pub async fn leave_all(app_state: &AppState, user: &str) {
+    let rooms = app_state.get_rooms().await;
+    for room in rooms.iter().filter(|r| r.users.iter().any(|u| u == user)) {
+        remove_member(app_state, &room.room, user).await;
+    }
+}
+
+pub async fn leave_room(
+    app_state: &AppState,
+    tx: &UnboundedSender<Message>,
+    room: String,
+    user: String,
+) {
+    if let Some(r) = remove_member(app_state, &room, &user).await
+        && let Err(err) = tx.send(Message::Text(
+            serde_json::json!({
+                "type":"room_left",
+                "room":r.room
+            })
+            .to_string()
+            .into(),
+        ))
+    {
+        error!("Error while sending message {}", err.to_string());
+    }
 }
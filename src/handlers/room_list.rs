@@ -0,0 +1,410 @@
+use std::cmp::Ordering;
+
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{error, info};
+
+use crate::{
+    app_state::AppState,
+    types::{Room, RoomListFilter, RoomListSort, RoomListSubscription},
+};
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 100;
+
+fn matches(room: &Room, subscription: &RoomListSubscription) -> bool {
+    !room.is_archived()
+        && subscription.filter.prefix.as_ref().is_none_or(|prefix| {
+            room.room_name
+                .to_lowercase()
+                .starts_with(&prefix.to_lowercase())
+        })
+        && (!subscription.filter.joined || room.users.contains(&subscription.user))
+}
+
+fn compare(sort: RoomListSort, a: &Room, b: &Room) -> Ordering {
+    match sort {
+        RoomListSort::Activity => b.last_activity().cmp(&a.last_activity()),
+        RoomListSort::Members => b.users.len().cmp(&a.users.len()),
+        RoomListSort::Name => a.room_name.to_lowercase().cmp(&b.room_name.to_lowercase()),
+    }
+    .then_with(|| a.room.cmp(&b.room))
+}
+
+// Rows of the requested page and the total number of matching rooms.
+fn query(rooms: &[Room], subscription: &RoomListSubscription) -> (Vec<serde_json::Value>, usize) {
+    let mut matching: Vec<&Room> = rooms.iter().filter(|r| matches(r, subscription)).collect();
+    matching.sort_by(|a, b| compare(subscription.sort, a, b));
+    let page = matching
+        .iter()
+        .skip(subscription.cursor)
+        .take(subscription.limit)
+        .map(|r| r.summary())
+        .collect();
+    (page, matching.len())
+}
+
+fn send(tx: &UnboundedSender<Message>, message: serde_json::Value) {
+    if let Err(e) = tx.send(Message::Text(message.to_string().into())) {
+        error!("Error while sending room list update: {:?}", e);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn subscribe(
+    app_state: &AppState,
+    user_id: &str,
+    connection_id: &str,
+    filter: RoomListFilter,
+    sort: RoomListSort,
+    limit: Option<usize>,
+    cursor: Option<usize>,
+    tx: &UnboundedSender<Message>,
+) {
+    let mut subscription = RoomListSubscription {
+        user: user_id.to_owned(),
+        tx: tx.clone(),
+        filter,
+        sort,
+        limit: limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE),
+        cursor: cursor.unwrap_or(0),
+        view: Vec::new(),
+    };
+    let rooms = app_state.get_rooms().await;
+    let (page, total) = query(&rooms, &subscription);
+    let next_cursor = subscription.cursor + page.len();
+    send(
+        tx,
+        serde_json::json!({
+            "type":"room_list",
+            "rooms":page,
+            "cursor":subscription.cursor,
+            "next_cursor":(next_cursor < total).then_some(next_cursor),
+            "total":total
+        }),
+    );
+    subscription.view = page;
+    info!("Connection {} subscribed to the room list", connection_id);
+    app_state
+        .room_lists
+        .lock()
+        .await
+        .insert(connection_id.to_owned(), subscription);
+}
+
+pub async fn unsubscribe(app_state: &AppState, connection_id: &str) {
+    app_state.room_lists.lock().await.remove(connection_id);
+}
+
+// Pushes the effect of a change to `room` to every subscriber, as a diff from
+// what they were last sent. Call after anything that can move the room in or
+// out of a view or change its row, including deleting it.
+pub async fn refresh(app_state: &AppState, room: &str) {
+    if app_state.room_lists.lock().await.is_empty() {
+        return;
+    }
+    let rooms = app_state.get_rooms().await;
+    let changed = rooms.iter().find(|r| r.room == room);
+    let mut room_lists = app_state.room_lists.lock().await;
+    for subscription in room_lists.values_mut() {
+        if !apply_change(&rooms, changed, room, subscription) {
+            requery(&rooms, subscription);
+        }
+    }
+}
+
+// Moves, adds or drops the changed room's row by finding its rank, which
+// doesn't need the other rooms sorted. Returns false when rows besides the
+// changed one would enter or leave the page, which takes a full query.
+fn apply_change(
+    rooms: &[Room],
+    changed: Option<&Room>,
+    room: &str,
+    subscription: &mut RoomListSubscription,
+) -> bool {
+    let changed = changed.filter(|r| matches(r, subscription));
+    let old = subscription.view.iter().position(|row| row["room"] == room);
+    let new = changed
+        .and_then(|changed| {
+            let rank = rooms
+                .iter()
+                .filter(|r| {
+                    matches(r, subscription)
+                        && compare(subscription.sort, r, changed) == Ordering::Less
+                })
+                .count();
+            rank.checked_sub(subscription.cursor)
+        })
+        .filter(|index| *index < subscription.limit);
+    // On the first page, a room that isn't on it before or after can't
+    // have pushed anything else on or off.
+    let first_page = subscription.cursor == 0;
+    match (old, new, changed) {
+        (None, None, _) if first_page => {}
+        (Some(old), Some(new), Some(changed)) => {
+            let row = changed.summary();
+            if old != new || subscription.view[old] != row {
+                send_row(subscription, "room_list_update", &row, new);
+            }
+            subscription.view.remove(old);
+            subscription.view.insert(new, row);
+        }
+        (None, Some(new), Some(changed)) if first_page => {
+            if subscription.view.len() == subscription.limit
+                && let Some(last) = subscription.view.pop()
+            {
+                send_remove(subscription, &last["room"]);
+            }
+            let row = changed.summary();
+            send_row(subscription, "room_list_add", &row, new);
+            subscription.view.insert(new, row);
+        }
+        _ => return false,
+    }
+    true
+}
+
+fn send_row(
+    subscription: &RoomListSubscription,
+    kind: &str,
+    row: &serde_json::Value,
+    index: usize,
+) {
+    send(
+        &subscription.tx,
+        serde_json::json!({
+            "type":kind,
+            "room":row,
+            "index":subscription.cursor + index
+        }),
+    );
+}
+
+fn send_remove(subscription: &RoomListSubscription, room: &serde_json::Value) {
+    send(
+        &subscription.tx,
+        serde_json::json!({
+            "type":"room_list_remove",
+            "room":room
+        }),
+    );
+}
+
+// Re-runs the subscriber's query and diffs the whole page.
+fn requery(rooms: &[Room], subscription: &mut RoomListSubscription) {
+    let (page, _) = query(rooms, subscription);
+    for old in &subscription.view {
+        if !page.iter().any(|row| row["room"] == old["room"]) {
+            send_remove(subscription, &old["room"]);
+        }
+    }
+    for (index, row) in page.iter().enumerate() {
+        let previous = subscription
+            .view
+            .iter()
+            .position(|old| old["room"] == row["room"]);
+        let kind = match previous {
+            None => "room_list_add",
+            Some(i) if i != index || subscription.view[i] != *row => "room_list_update",
+            Some(_) => continue,
+        };
+        send_row(subscription, kind, row, index);
+    }
+    subscription.view = page;
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::{UnboundedReceiver, unbounded_channel};
+
+    use super::*;
+
+    fn room(id: &str, last_activity_at: u64) -> Room {
+        serde_json::from_value(serde_json::json!({
+            "room":id,
+            "room_name":id,
+            "messages":[],
+            "users":[],
+            "admin":"admin",
+            "last_activity_at":last_activity_at
+        }))
+        .unwrap()
+    }
+
+    fn subscribe(
+        rooms: &[Room],
+        limit: usize,
+        cursor: usize,
+    ) -> (RoomListSubscription, UnboundedReceiver<Message>) {
+        let (tx, rx) = unbounded_channel();
+        let mut subscription = RoomListSubscription {
+            user: "user".to_owned(),
+            tx,
+            filter: RoomListFilter::default(),
+            sort: RoomListSort::Activity,
+            limit,
+            cursor,
+            view: Vec::new(),
+        };
+        subscription.view = query(rooms, &subscription).0;
+        (subscription, rx)
+    }
+
+    // (type, room id, index) of every event sent so far.
+    fn events(rx: &mut UnboundedReceiver<Message>) -> Vec<(String, String, Option<u64>)> {
+        let mut events = Vec::new();
+        while let Ok(message) = rx.try_recv() {
+            let event: serde_json::Value =
+                serde_json::from_str(message.to_text().unwrap()).unwrap();
+            let room = match &event["room"] {
+                serde_json::Value::String(room) => room.clone(),
+                row => row["room"].as_str().unwrap().to_owned(),
+            };
+            events.push((
+                event["type"].as_str().unwrap().to_owned(),
+                room,
+                event["index"].as_u64(),
+            ));
+        }
+        events
+    }
+
+    fn view(subscription: &RoomListSubscription) -> Vec<&str> {
+        subscription
+            .view
+            .iter()
+            .map(|row| row["room"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn moves_a_row_within_the_page() {
+        let mut rooms = vec![room("a", 30), room("b", 20), room("c", 10)];
+        let (mut subscription, mut rx) = subscribe(&rooms, 10, 0);
+        rooms[2].last_activity_at = 40;
+        assert!(apply_change(
+            &rooms,
+            Some(&rooms[2]),
+            "c",
+            &mut subscription
+        ));
+        assert_eq!(
+            events(&mut rx),
+            [("room_list_update".to_owned(), "c".to_owned(), Some(0))]
+        );
+        assert_eq!(view(&subscription), ["c", "a", "b"]);
+    }
+
+    #[test]
+    fn unchanged_row_sends_nothing() {
+        let rooms = vec![room("a", 30), room("b", 20)];
+        let (mut subscription, mut rx) = subscribe(&rooms, 10, 0);
+        assert!(apply_change(
+            &rooms,
+            Some(&rooms[1]),
+            "b",
+            &mut subscription
+        ));
+        assert!(events(&mut rx).is_empty());
+    }
+
+    #[test]
+    fn added_room_pushes_the_last_row_off_a_full_page() {
+        let mut rooms = vec![room("a", 30), room("b", 20)];
+        let (mut subscription, mut rx) = subscribe(&rooms, 2, 0);
+        rooms.push(room("d", 50));
+        assert!(apply_change(
+            &rooms,
+            Some(&rooms[2]),
+            "d",
+            &mut subscription
+        ));
+        assert_eq!(
+            events(&mut rx),
+            [
+                ("room_list_remove".to_owned(), "b".to_owned(), None),
+                ("room_list_add".to_owned(), "d".to_owned(), Some(0)),
+            ]
+        );
+        assert_eq!(view(&subscription), ["d", "a"]);
+    }
+
+    #[test]
+    fn room_outside_the_first_page_sends_nothing() {
+        let rooms = vec![room("a", 30), room("b", 20), room("c", 10)];
+        let (mut subscription, mut rx) = subscribe(&rooms, 2, 0);
+        assert!(apply_change(
+            &rooms,
+            Some(&rooms[2]),
+            "c",
+            &mut subscription
+        ));
+        assert!(events(&mut rx).is_empty());
+        assert_eq!(view(&subscription), ["a", "b"]);
+    }
+
+    #[test]
+    fn deleted_room_falls_back_to_requery() {
+        let mut rooms = vec![room("a", 30), room("b", 20), room("c", 10)];
+        let (mut subscription, mut rx) = subscribe(&rooms, 2, 0);
+        rooms.remove(0);
+        assert!(!apply_change(&rooms, None, "a", &mut subscription));
+        assert!(events(&mut rx).is_empty());
+        requery(&rooms, &mut subscription);
+        assert_eq!(
+            events(&mut rx),
+            [
+                ("room_list_remove".to_owned(), "a".to_owned(), None),
+                ("room_list_update".to_owned(), "b".to_owned(), Some(0)),
+                ("room_list_add".to_owned(), "c".to_owned(), Some(1)),
+            ]
+        );
+        assert_eq!(view(&subscription), ["b", "c"]);
+    }
+
+    #[test]
+    fn archived_room_leaves_the_view() {
+        let mut rooms = vec![room("a", 30), room("b", 20)];
+        let (mut subscription, mut rx) = subscribe(&rooms, 10, 0);
+        rooms[0].archived_at = Some(1);
+        assert!(!apply_change(
+            &rooms,
+            Some(&rooms[0]),
+            "a",
+            &mut subscription
+        ));
+        requery(&rooms, &mut subscription);
+        assert_eq!(
+            events(&mut rx),
+            [
+                ("room_list_remove".to_owned(), "a".to_owned(), None),
+                ("room_list_update".to_owned(), "b".to_owned(), Some(0)),
+            ]
+        );
+        assert_eq!(view(&subscription), ["b"]);
+    }
+
+    #[test]
+    fn requery_on_a_later_page_offsets_indices() {
+        let mut rooms = vec![room("a", 30), room("b", 20), room("c", 10)];
+        let (mut subscription, mut rx) = subscribe(&rooms, 2, 1);
+        assert_eq!(view(&subscription), ["b", "c"]);
+        rooms[2].last_activity_at = 40;
+        assert!(!apply_change(
+            &rooms,
+            Some(&rooms[2]),
+            "c",
+            &mut subscription
+        ));
+        requery(&rooms, &mut subscription);
+        assert_eq!(
+            events(&mut rx),
+            [
+                ("room_list_remove".to_owned(), "c".to_owned(), None),
+                ("room_list_add".to_owned(), "a".to_owned(), Some(1)),
+                ("room_list_update".to_owned(), "b".to_owned(), Some(2)),
+            ]
+        );
+        assert_eq!(view(&subscription), ["a", "b"]);
+    }
+}
@@ -0,0 +1,100 @@
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::error;
+
+use crate::{
+    app_state::AppState,
+    handlers::send_error,
+    search::SearchQuery,
+    types::{MessageSearch, Room},
+};
+
+const DEFAULT_PAGE_SIZE: usize = 20;
+const MAX_PAGE_SIZE: usize = 50;
+const MAX_QUERY_LEN: usize = 256;
+// Deepest page reachable by cursor; tantivy collects `offset + limit` hits.
+const MAX_OFFSET: usize = 1000;
+
+// Members can search their rooms; former members keep access to archived ones.
+fn searchable(room: &Room, user_id: &str) -> bool {
+    room.users.iter().any(|u| u == user_id) || (room.is_archived() && room.can_read(user_id))
+}
+
+pub async fn search(
+    app_state: &AppState,
+    user_id: &str,
+    search: MessageSearch,
+    tx: &UnboundedSender<Message>,
+) {
+    let text = search.query.trim().to_owned();
+    if text.is_empty() || text.len() > MAX_QUERY_LEN {
+        send_error(tx, "invalid_query", "Search query is empty or too long");
+        return;
+    }
+    let rooms = app_state.get_rooms().await;
+    let allowed: Vec<&Room> = rooms.iter().filter(|r| searchable(r, user_id)).collect();
+    let room_ids: Vec<String> = match &search.room {
+        Some(room) if allowed.iter().any(|r| r.room == *room) => vec![room.clone()],
+        Some(_) => {
+            send_error(tx, "forbidden", "Not a member of this room");
+            return;
+        }
+        None => allowed.iter().map(|r| r.room.clone()).collect(),
+    };
+
+    let offset = search.cursor.unwrap_or(0).min(MAX_OFFSET);
+    let query = SearchQuery {
+        text,
+        rooms: room_ids,
+        from: search.from,
+        before: search.before,
+        after: search.after,
+        offset,
+        limit: search
+            .limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE),
+    };
+    let index = app_state.search.clone();
+    let result = tokio::task::spawn_blocking(move || index.search(&query)).await;
+    let (hits, total) = match result {
+        Ok(Ok(found)) => found,
+        Ok(Err(e)) => {
+            error!("Error while searching messages: {:?}", e);
+            send_error(tx, "search_failed", "Search failed");
+            return;
+        }
+        Err(e) => {
+            error!("Search task failed: {:?}", e);
+            send_error(tx, "search_failed", "Search failed");
+            return;
+        }
+    };
+
+    let results: Vec<serde_json::Value> = hits
+        .into_iter()
+        .map(|hit| {
+            let room_name = allowed
+                .iter()
+                .find(|r| r.room == hit.room)
+                .map(|r| r.room_name.clone());
+            let mut value = serde_json::to_value(hit).unwrap_or_default();
+            value["room_name"] = room_name.into();
+            value
+        })
+        .collect();
+    let next_cursor = offset.saturating_add(results.len());
+    if let Err(e) = tx.send(Message::Text(
+        serde_json::json!({
+            "type":"search_results",
+            "query":search.query,
+            "results":results,
+            "total":total,
+            "next_cursor":(next_cursor < total && next_cursor <= MAX_OFFSET).then_some(next_cursor)
+        })
+        .to_string()
+        .into(),
+    )) {
+        error!("Error while sending search results: {:?}", e);
+    }
+}
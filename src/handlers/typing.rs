@@ -0,0 +1,133 @@
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::{
+    app_state::AppState,
+    handlers::{room::broadcast_to_others, send_error},
+    types::{Room, TypingState},
+};
+
+// Typing indicators are never persisted; they expire on their own unless the
+// client keeps refreshing them, and repeated starts within the refresh window
+// only extend the expiry instead of fanning out again.
+const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+const TYPING_REFRESH: Duration = Duration::from_secs(3);
+
+fn typing_event(kind: &str, room: &str, user: &str) -> String {
+    serde_json::json!({
+        "type":kind,
+        "room":room,
+        "user":user,
+        "expires_in":TYPING_TIMEOUT.as_millis() as u64
+    })
+    .to_string()
+}
+
+pub async fn start(
+    app_state: &AppState,
+    user_id: &String,
+    room: String,
+    tx: &UnboundedSender<Message>,
+) {
+    let Some(r) = app_state.find_room(&room).await else {
+        send_error(tx, "not_found", "Room not found");
+        return;
+    };
+    if !r.users.contains(user_id) {
+        send_error(tx, "forbidden", "Not a member of this room");
+        return;
+    }
+    if r.is_archived() {
+        return;
+    }
+
+    let now = Instant::now();
+    let key = (room.clone(), user_id.clone());
+    let (notify, spawn_timer) = {
+        let mut typing = app_state.typing.lock().await;
+        match typing.get_mut(&key) {
+            Some(state) => {
+                state.expires_at = now + TYPING_TIMEOUT;
+                let notify = now.duration_since(state.last_sent) >= TYPING_REFRESH;
+                if notify {
+                    state.last_sent = now;
+                }
+                (notify, false)
+            }
+            None => {
+                typing.insert(
+                    key.clone(),
+                    TypingState {
+                        expires_at: now + TYPING_TIMEOUT,
+                        last_sent: now,
+                    },
+                );
+                (true, true)
+            }
+        }
+    };
+
+    if notify {
+        broadcast_to_others(
+            app_state,
+            &r,
+            user_id,
+            typing_event("typing_start", &room, user_id),
+        )
+        .await;
+    }
+    if spawn_timer {
+        tokio::spawn(expire(app_state.clone(), key));
+    }
+}
+
+async fn expire(app_state: AppState, key: (String, String)) {
+    loop {
+        let expires_at = match app_state.typing.lock().await.get(&key) {
+            Some(state) => state.expires_at,
+            None => return,
+        };
+        tokio::time::sleep_until(expires_at.into()).await;
+
+        let mut typing = app_state.typing.lock().await;
+        match typing.get(&key) {
+            Some(state) if state.expires_at <= Instant::now() => {
+                typing.remove(&key);
+                break;
+            }
+            Some(_) => continue,
+            None => return,
+        }
+    }
+
+    let (room, user) = key;
+    if let Some(r) = app_state.find_room(&room).await {
+        broadcast_to_others(
+            &app_state,
+            &r,
+            &user,
+            typing_event("typing_stop", &room, &user),
+        )
+        .await;
+    }
+}
+
+pub async fn stop(app_state: &AppState, room: &Room, user_id: &str) {
+    let removed = app_state
+        .typing
+        .lock()
+        .await
+        .remove(&(room.room.clone(), user_id.to_owned()))
+        .is_some();
+    if removed {
+        broadcast_to_others(
+            app_state,
+            room,
+            user_id,
+            typing_event("typing_stop", &room.room, user_id),
+        )
+        .await;
+    }
+}
@@ -0,0 +1,399 @@
+use std::{io, time::Duration};
+
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::{
+    app_state::{AppState, UPLOAD_TTL_SECS},
+    blobs::BlobStore,
+    handlers::{
+        room::{broadcast_message, ensure_writable},
+        send_error,
+    },
+    media,
+    types::{Attachment, Thumbnail, Upload, UploadRequest},
+    utils::now,
+};
+
+// Binary frame layout for upload chunks: the magic, the upload id as 16 raw
+// UUID bytes, the chunk's byte offset as a big-endian u64, then the data.
+const CHUNK_MAGIC: &[u8; 4] = b"RTCU";
+const CHUNK_HEADER_LEN: usize = 4 + 16 + 8;
+const MAX_NAME_LEN: usize = 255;
+const MAX_OPEN_UPLOADS: usize = 5;
+// Partial files idle this long with no upload record are removed.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+pub fn is_chunk(data: &[u8]) -> bool {
+    data.len() >= CHUNK_HEADER_LEN && data.starts_with(CHUNK_MAGIC)
+}
+
+fn send(tx: &UnboundedSender<Message>, message: serde_json::Value) {
+    if let Err(e) = tx.send(Message::Text(message.to_string().into())) {
+        error!("Error while sending upload status: {:?}", e);
+    }
+}
+
+fn send_started(app_state: &AppState, tx: &UnboundedSender<Message>, id: &str, offset: u64) {
+    send(
+        tx,
+        serde_json::json!({
+            "type":"upload_started",
+            "upload_id":id,
+            "offset":offset,
+            "chunk_size":app_state.config.upload_chunk_size
+        }),
+    );
+}
+
+async fn owned_upload(
+    app_state: &AppState,
+    user_id: &str,
+    upload_id: &str,
+    tx: &UnboundedSender<Message>,
+) -> Option<Upload> {
+    match app_state.get_upload(upload_id).await {
+        Some(upload) if upload.user == user_id => Some(upload),
+        _ => {
+            send_error(tx, "upload_not_found", "Upload not found");
+            None
+        }
+    }
+}
+
+pub async fn start(
+    app_state: &AppState,
+    user_id: &str,
+    request: UploadRequest,
+    tx: &UnboundedSender<Message>,
+) {
+    let config = &app_state.config;
+    let name = request.name.trim().to_owned();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN || name.contains(['/', '\\']) {
+        send_error(tx, "invalid_name", "Invalid file name");
+        return;
+    }
+    if request.size == 0 || request.size > config.max_upload_size {
+        send_error(tx, "file_too_large", "File is empty or too large");
+        return;
+    }
+    let mime = request.mime.trim().to_lowercase();
+    if !config.allowed_mime_types.contains(&mime) {
+        send_error(tx, "unsupported_type", "File type is not allowed");
+        return;
+    }
+    let sha256 = request.sha256.to_lowercase();
+    if !BlobStore::is_blob_id(&sha256) {
+        send_error(tx, "invalid_checksum", "Expected a hex SHA-256 checksum");
+        return;
+    }
+    if request.message.len() > config.max_message_size {
+        send_error(tx, "message_too_large", "Message is too large");
+        return;
+    }
+    let Some(r) = app_state.find_room(&request.room).await else {
+        send_error(tx, "not_found", "Room not found");
+        return;
+    };
+    if !r.users.iter().any(|u| u == user_id) {
+        send_error(tx, "forbidden", "Not a member of this room");
+        return;
+    }
+    if !ensure_writable(&r, tx) {
+        return;
+    }
+    if app_state.open_uploads(user_id).await.len() >= MAX_OPEN_UPLOADS {
+        send_error(tx, "too_many_uploads", "Too many uploads in progress");
+        return;
+    }
+
+    let upload = Upload {
+        id: Uuid::new_v4().to_string(),
+        user: user_id.to_owned(),
+        room: request.room,
+        name,
+        size: request.size,
+        mime,
+        sha256,
+        message: request.message,
+        reply_to: request.reply_to,
+        created_at: now(),
+    };
+    app_state.set_upload(&upload).await;
+    info!(
+        "User {} started upload {} ({} bytes)",
+        user_id, upload.id, upload.size
+    );
+    send_started(app_state, tx, &upload.id, 0);
+}
+
+pub async fn resume(
+    app_state: &AppState,
+    user_id: &str,
+    upload_id: String,
+    tx: &UnboundedSender<Message>,
+) {
+    if owned_upload(app_state, user_id, &upload_id, tx)
+        .await
+        .is_some()
+    {
+        let offset = app_state.blobs.received(&upload_id).await;
+        send_started(app_state, tx, &upload_id, offset);
+    }
+}
+
+pub async fn cancel(
+    app_state: &AppState,
+    user_id: &str,
+    upload_id: String,
+    tx: &UnboundedSender<Message>,
+) {
+    if let Some(upload) = owned_upload(app_state, user_id, &upload_id, tx).await {
+        app_state.blobs.discard(&upload_id).await;
+        app_state.del_upload(&upload).await;
+        send(
+            tx,
+            serde_json::json!({
+                "type":"upload_cancelled",
+                "upload_id":upload_id
+            }),
+        );
+    }
+}
+
+pub async fn chunk(
+    app_state: &AppState,
+    user_id: &str,
+    data: &[u8],
+    tx: &UnboundedSender<Message>,
+) {
+    let Ok(upload_id) = Uuid::from_slice(&data[4..20]) else {
+        send_error(tx, "upload_not_found", "Upload not found");
+        return;
+    };
+    let upload_id = upload_id.to_string();
+    let offset = u64::from_be_bytes(data[20..28].try_into().unwrap());
+    let payload = &data[CHUNK_HEADER_LEN..];
+    let Some(upload) = owned_upload(app_state, user_id, &upload_id, tx).await else {
+        return;
+    };
+    if payload.is_empty() || payload.len() > app_state.config.upload_chunk_size {
+        send_error(tx, "invalid_chunk", "Chunk is empty or too large");
+        return;
+    }
+
+    let received = app_state.blobs.received(&upload_id).await;
+    if offset != received {
+        // Not an error the client should give up on: it resends from here.
+        send(
+            tx,
+            serde_json::json!({
+                "type":"error",
+                "code":"offset_mismatch",
+                "message":"Chunk does not continue the upload",
+                "upload_id":upload_id,
+                "offset":received
+            }),
+        );
+        return;
+    }
+    if received + payload.len() as u64 > upload.size {
+        send_error(tx, "file_too_large", "More data than the declared size");
+        return;
+    }
+
+    let received = match app_state.blobs.append(&upload_id, payload).await {
+        Ok(received) => received,
+        Err(e) => {
+            error!("Error while writing upload {}: {:?}", upload_id, e);
+            send_error(tx, "upload_failed", "Could not store chunk");
+            return;
+        }
+    };
+    if received < upload.size {
+        send(
+            tx,
+            serde_json::json!({
+                "type":"upload_progress",
+                "upload_id":upload_id,
+                "offset":received
+            }),
+        );
+        return;
+    }
+
+    app_state.del_upload(&upload).await;
+    let stored = if upload.mime.starts_with("image/") {
+        store_image(app_state, &upload).await
+    } else {
+        app_state
+            .blobs
+            .finish(&upload_id, &upload.sha256)
+            .await
+            .map(|blob_id| {
+                blob_id
+                    .map(|blob_id| attachment(&upload, blob_id))
+                    .ok_or(Rejected::Checksum)
+            })
+    };
+    match stored {
+        Ok(Ok(attachment)) => complete(app_state, upload, attachment, tx).await,
+        Ok(Err(Rejected::Checksum)) => {
+            send_error(tx, "checksum_mismatch", "File does not match its checksum")
+        }
+        Ok(Err(Rejected::Image)) => send_error(tx, "invalid_image", "Image could not be read"),
+        Err(e) => {
+            error!("Error while storing upload {}: {:?}", upload_id, e);
+            send_error(tx, "upload_failed", "Could not store file");
+        }
+    }
+}
+
+fn attachment(upload: &Upload, blob_id: String) -> Attachment {
+    Attachment {
+        blob_id,
+        name: upload.name.clone(),
+        size: upload.size,
+        mime: upload.mime.clone(),
+        width: None,
+        height: None,
+        thumbnail: None,
+    }
+}
+
+// Why a finished upload wasn't stored.
+enum Rejected {
+    Checksum,
+    // Its metadata couldn't be stripped.
+    Image,
+}
+
+// Images are stripped of their metadata before they're stored, so the
+// original never becomes a blob that other uploads could share, and get
+// dimensions and a thumbnail. Images that can't be parsed for stripping are
+// rejected; ones that strip but fail to decode are attached without the
+// latter.
+async fn store_image(
+    app_state: &AppState,
+    upload: &Upload,
+) -> io::Result<Result<Attachment, Rejected>> {
+    let Some(data) = app_state
+        .blobs
+        .take_upload(&upload.id, &upload.sha256)
+        .await?
+    else {
+        return Ok(Err(Rejected::Checksum));
+    };
+    let thumbnail_size = app_state.config.thumbnail_size;
+    let permit = app_state
+        .image_decodes
+        .acquire()
+        .await
+        .map_err(io::Error::other)?;
+    let stripped = tokio::task::spawn_blocking(move || {
+        let data = match media::strip_metadata(&data) {
+            Ok(stripped) => stripped.unwrap_or(data),
+            Err(media::Malformed) => return None,
+        };
+        let info = media::inspect(&data, thumbnail_size);
+        Some((data, info))
+    })
+    .await
+    .map_err(io::Error::other)?;
+    drop(permit);
+    let Some((data, info)) = stripped else {
+        info!(
+            "Rejected upload {}: could not strip image metadata",
+            upload.id
+        );
+        return Ok(Err(Rejected::Image));
+    };
+
+    let mut attachment = attachment(upload, app_state.blobs.store(&data).await?);
+    attachment.size = data.len() as u64;
+    let info = match info {
+        Ok(info) => info,
+        Err(e) => {
+            info!("Could not decode image {}: {:?}", attachment.blob_id, e);
+            return Ok(Ok(attachment));
+        }
+    };
+    attachment.width = Some(info.width);
+    attachment.height = Some(info.height);
+    if let Some(thumbnail) = info.thumbnail {
+        attachment.thumbnail = Some(Thumbnail {
+            blob_id: app_state.blobs.store(&thumbnail.data).await?,
+            width: thumbnail.width,
+            height: thumbnail.height,
+            mime: thumbnail.mime.to_owned(),
+        });
+    }
+    Ok(Ok(attachment))
+}
+
+// Posts the finished attachment to the room it was uploaded for.
+async fn complete(
+    app_state: &AppState,
+    upload: Upload,
+    attachment: Attachment,
+    tx: &UnboundedSender<Message>,
+) {
+    info!("Upload {} stored as blob {}", upload.id, attachment.blob_id);
+    let member = app_state
+        .find_room(&upload.room)
+        .await
+        .is_some_and(|r| r.users.contains(&upload.user));
+    if !member {
+        send_error(tx, "forbidden", "Not a member of this room");
+        return;
+    }
+    send(
+        tx,
+        serde_json::json!({
+            "type":"upload_complete",
+            "upload_id":upload.id,
+            "blob_id":attachment.blob_id
+        }),
+    );
+    broadcast_message(
+        app_state,
+        upload.message,
+        None,
+        upload.room,
+        upload.user,
+        upload.reply_to,
+        Some(attachment),
+        tx,
+    )
+    .await;
+}
+
+// Deletes partial upload files that were abandoned: their record expired
+// with `UPLOAD_TTL_SECS`, or outlived it.
+pub async fn sweeper(app_state: AppState) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let ids = match app_state.blobs.idle_uploads(SWEEP_INTERVAL).await {
+            Ok(ids) => ids,
+            Err(e) => {
+                error!("Error while listing partial uploads: {:?}", e);
+                continue;
+            }
+        };
+        let expired_before = now().saturating_sub(UPLOAD_TTL_SECS as u64 * 1000);
+        for id in ids {
+            let upload = app_state.get_upload(&id).await;
+            if let Some(upload) = &upload {
+                if upload.created_at >= expired_before {
+                    continue;
+                }
+                app_state.del_upload(upload).await;
+            }
+            app_state.blobs.discard(&id).await;
+            info!("Removed abandoned upload {}", id);
+        }
+    }
+}
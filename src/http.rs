@@ -0,0 +1,208 @@
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{error, info};
+
+use crate::{app_state::AppState, blobs::BlobStore};
+
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+struct Request {
+    method: String,
+    path: String,
+    query: Option<String>,
+    authorization: Option<String>,
+}
+
+// Reads the request line and headers; bodies aren't supported.
+async fn read_request(stream: &mut TcpStream) -> Option<Request> {
+    let mut head = Vec::new();
+    let mut buffer = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let read = stream.read(&mut buffer).await.ok()?;
+        if read == 0 || head.len() + read > MAX_REQUEST_HEAD {
+            return None;
+        }
+        head.extend_from_slice(&buffer[..read]);
+    }
+    let head = String::from_utf8(head).ok()?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_owned();
+    let target = request_line.next()?;
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_owned(), Some(query.to_owned())),
+        None => (target.to_owned(), None),
+    };
+    let authorization = lines.find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.eq_ignore_ascii_case("authorization")
+            .then(|| value.trim().to_owned())
+    });
+    Some(Request {
+        method,
+        path,
+        query,
+        authorization,
+    })
+}
+
+impl Request {
+    fn param(&self, name: &str) -> Option<&str> {
+        self.query.as_deref()?.split('&').find_map(|pair| {
+            let (key, value) = pair.split_once('=')?;
+            (key == name && !value.is_empty()).then_some(value)
+        })
+    }
+
+    // Session token from `Authorization: Bearer <token>` or `?session=<token>`.
+    fn session(&self) -> Option<&str> {
+        if let Some(token) = self
+            .authorization
+            .as_deref()
+            .and_then(|auth| auth.strip_prefix("Bearer "))
+        {
+            return Some(token.trim());
+        }
+        self.param("session")
+    }
+}
+
+async fn respond(stream: &mut TcpStream, status: &str) {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+}
+
+// Content type and file name of a blob attached, or the thumbnail of an
+// image attached, in a room the user can read.
+pub async fn find_file(
+    app_state: &AppState,
+    user: &str,
+    blob_id: &str,
+) -> Option<(String, String)> {
+    app_state
+        .get_rooms()
+        .await
+        .into_iter()
+        .filter(|r| r.users.iter().any(|u| u == user) || (r.is_archived() && r.can_read(user)))
+        .flat_map(|r| r.messages)
+        .filter(|m| !m.deleted)
+        .find_map(|m| {
+            let attachment = m.attachment?;
+            if attachment.blob_id == blob_id {
+                return Some((attachment.mime, attachment.name));
+            }
+            attachment
+                .thumbnail
+                .filter(|t| t.blob_id == blob_id)
+                .map(|t| (t.mime, attachment.name))
+        })
+}
+
+// Avatars are public like the rest of a profile, so any signed-in user can
+// fetch the blob `owner` has set as theirs. The type comes from the content,
+// since `set_profile` only lets users pick images.
+async fn find_avatar(app_state: &AppState, owner: &str, blob_id: &str) -> Option<(String, String)> {
+    let profile = app_state.get_profile(owner).await;
+    if profile.avatar.as_deref() != Some(blob_id) {
+        return None;
+    }
+    let mut head = [0; 32];
+    let mut file = fs::File::open(app_state.blobs.blob_path(blob_id))
+        .await
+        .ok()?;
+    let read = file.read(&mut head).await.ok()?;
+    let format = image::guess_format(&head[..read]).ok()?;
+    Some((format.to_mime_type().to_owned(), "avatar".to_owned()))
+}
+
+async fn handle(mut stream: TcpStream, app_state: AppState) {
+    let Some(request) = read_request(&mut stream).await else {
+        respond(&mut stream, "400 Bad Request").await;
+        return;
+    };
+    if request.method != "GET" && request.method != "HEAD" {
+        respond(&mut stream, "405 Method Not Allowed").await;
+        return;
+    }
+    let Some(blob_id) = request.path.strip_prefix("/blobs/") else {
+        respond(&mut stream, "404 Not Found").await;
+        return;
+    };
+    if !BlobStore::is_blob_id(blob_id) {
+        respond(&mut stream, "404 Not Found").await;
+        return;
+    }
+    let user = match request.session() {
+        Some(session) => app_state.resume_session(session).await,
+        None => None,
+    };
+    let Some(user) = user else {
+        respond(&mut stream, "401 Unauthorized").await;
+        return;
+    };
+    // Blobs are only reachable through a message the user can see or the
+    // profile named by `?user=`, so knowing a hash isn't enough to fetch a file.
+    let file = match find_file(&app_state, &user, blob_id).await {
+        Some(file) => Some(file),
+        None => match request.param("user") {
+            Some(owner) => find_avatar(&app_state, owner, blob_id).await,
+            None => None,
+        },
+    };
+    let Some((mime, name)) = file else {
+        respond(&mut stream, "404 Not Found").await;
+        return;
+    };
+    let mut file = match fs::File::open(app_state.blobs.blob_path(blob_id)).await {
+        Ok(file) => file,
+        Err(e) => {
+            error!("Error while opening blob {}: {:?}", blob_id, e);
+            respond(&mut stream, "404 Not Found").await;
+            return;
+        }
+    };
+    let length = file.metadata().await.map_or(0, |meta| meta.len());
+    let disposition = if mime.starts_with("image/") {
+        "inline"
+    } else {
+        "attachment"
+    };
+    let filename: String = name
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"' && *c != '\\')
+        .collect();
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nContent-Disposition: {}; filename=\"{}\"\r\nX-Content-Type-Options: nosniff\r\nCache-Control: private, max-age=31536000, immutable\r\nConnection: close\r\n\r\n",
+        mime, length, disposition, filename
+    );
+    if stream.write_all(head.as_bytes()).await.is_err() || request.method == "HEAD" {
+        return;
+    }
+    if let Err(e) = tokio::io::copy(&mut file, &mut stream).await {
+        info!("Download of blob {} interrupted: {:?}", blob_id, e);
+    }
+}
+
+// Serves attachment downloads at `/blobs/<blob id>` to users who can read a
+// room the attachment was posted in, and avatars at
+// `/blobs/<blob id>?user=<user id>`.
+pub async fn serve(app_state: AppState) {
+    let addr = app_state.config.http_addr.clone();
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Error while binding HTTP server to {}: {:?}", addr, e);
+            return;
+        }
+    };
+    info!("HTTP server running on http://{}", addr);
+    while let Ok((stream, _)) = listener.accept().await {
+        tokio::spawn(handle(stream, app_state.clone()));
+    }
+}
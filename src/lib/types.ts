@@ -44,6 +44,17 @@ interface RoomAvailable {
 	room_id: string;
 	room_name: string;
 }
+interface RoomDeleted {
+	type: "room_deleted";
+	room: string;
+	version: number;
+}
+interface RoomRenamed {
+	type: "room_renamed";
+	room: string;
+	room_name: string;
+	version: number;
+}
 
 interface Info {
 	type: "info";
@@ -58,4 +69,6 @@ type Messages =
 	| RoomCreated
 	| RoomJoined
 	| RoomAvailable
+	| RoomDeleted
+	| RoomRenamed
 	| Info;
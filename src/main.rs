@@ -5,9 +5,19 @@ use tracing::info;
 use crate::{app_state::AppState, handlers::connections::handle_connection};
 
 mod app_state;
+mod blobs;
+mod compression;
+mod config;
+mod encoding;
 mod handlers;
+mod http;
+mod media;
+mod protocol;
 mod redis;
+mod rich_text;
+mod search;
 mod types;
+mod utils;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -17,6 +27,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let listener = TcpListener::bind(addr).await?;
     info!("WebSocket server running on ws://{}", addr);
     let app_state = AppState::new();
+    app_state.backfill_search_index().await;
+    tokio::spawn(http::serve(app_state.clone()));
+    tokio::spawn(handlers::presence::idle_watcher(app_state.clone()));
+    tokio::spawn(handlers::lifecycle::reaper(app_state.clone()));
+    tokio::spawn(handlers::retention::pruner(app_state.clone()));
+    tokio::spawn(handlers::upload::sweeper(app_state.clone()));
 
     while let Ok((stream, addr)) = listener.accept().await {
         info!("New connection from: {}", addr);
@@ -0,0 +1,429 @@
+use std::io::Cursor;
+
+use image::{
+    DynamicImage, GenericImageView, ImageDecoder, ImageFormat, ImageReader, ImageResult, Limits,
+    codecs::jpeg::JpegEncoder, metadata::Orientation,
+};
+
+const MAX_DIMENSION: u32 = 16_384;
+const MAX_DECODE_ALLOC: u64 = 128 * 1024 * 1024;
+const THUMBNAIL_QUALITY: u8 = 80;
+
+pub struct ThumbnailImage {
+    pub data: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+    pub mime: &'static str,
+}
+
+// Dimensions are as displayed, i.e. after applying the EXIF orientation.
+pub struct ImageInfo {
+    pub width: u32,
+    pub height: u32,
+    pub thumbnail: Option<ThumbnailImage>,
+}
+
+// Decodes an image to read its dimensions, and renders a thumbnail that fits
+// in `thumbnail_size` squared when the image is larger than that.
+pub fn inspect(data: &[u8], thumbnail_size: u32) -> ImageResult<ImageInfo> {
+    let mut reader = ImageReader::new(Cursor::new(data)).with_guessed_format()?;
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODE_ALLOC);
+    reader.limits(limits);
+    let mut decoder = reader.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+
+    let (width, height) = image.dimensions();
+    let thumbnail = if width > thumbnail_size || height > thumbnail_size {
+        Some(thumbnail(&image, thumbnail_size)?)
+    } else {
+        None
+    };
+    Ok(ImageInfo {
+        width,
+        height,
+        thumbnail,
+    })
+}
+
+// JPEG unless the image has transparency to keep.
+fn thumbnail(image: &DynamicImage, size: u32) -> ImageResult<ThumbnailImage> {
+    let thumbnail = image.thumbnail(size, size);
+    let mut data = Vec::new();
+    let mime = if thumbnail.color().has_alpha() {
+        thumbnail.write_to(&mut Cursor::new(&mut data), ImageFormat::Png)?;
+        "image/png"
+    } else {
+        let encoder = JpegEncoder::new_with_quality(&mut data, THUMBNAIL_QUALITY);
+        thumbnail.to_rgb8().write_with_encoder(encoder)?;
+        "image/jpeg"
+    };
+    Ok(ThumbnailImage {
+        data,
+        width: thumbnail.width(),
+        height: thumbnail.height(),
+        mime,
+    })
+}
+
+// The image's structure couldn't be followed, so metadata may be left in it.
+#[derive(Debug)]
+pub struct Malformed;
+
+// Removes EXIF, XMP and text metadata (camera details, GPS location) without
+// re-encoding the image. The orientation is the one tag that survives, so
+// photos still display upright. Returns None when there was nothing to strip,
+// and fails rather than pass through anything it can't parse.
+pub fn strip_metadata(data: &[u8]) -> Result<Option<Vec<u8>>, Malformed> {
+    let stripped = match image::guess_format(data).map_err(|_| Malformed)? {
+        ImageFormat::Jpeg => strip_jpeg(data),
+        ImageFormat::Png => strip_png(data),
+        ImageFormat::WebP => strip_webp(data),
+        // Formats without EXIF support.
+        _ => return Ok(None),
+    }
+    .ok_or(Malformed)?;
+    Ok((stripped != data).then_some(stripped))
+}
+
+// A minimal big-endian TIFF structure holding only the orientation tag.
+fn orientation_exif(orientation: Option<Orientation>) -> Option<Vec<u8>> {
+    let orientation = orientation?.to_exif();
+    if orientation == Orientation::NoTransforms.to_exif() {
+        return None;
+    }
+    let mut tiff = b"MM\0\x2a".to_vec();
+    tiff.extend_from_slice(&8u32.to_be_bytes());
+    tiff.extend_from_slice(&1u16.to_be_bytes());
+    tiff.extend_from_slice(&0x0112u16.to_be_bytes());
+    tiff.extend_from_slice(&3u16.to_be_bytes());
+    tiff.extend_from_slice(&1u32.to_be_bytes());
+    tiff.extend_from_slice(&[0, orientation, 0, 0]);
+    tiff.extend_from_slice(&0u32.to_be_bytes());
+    Some(tiff)
+}
+
+// Drops APP1 (EXIF, XMP) and APP13 (IPTC) segments ahead of the image data.
+fn strip_jpeg(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = data.get(..2)?.to_vec();
+    let mut orientation = None;
+    let mut pos = 2;
+    // Segments run up to the start of scan; anything else is malformed.
+    loop {
+        if *data.get(pos)? != 0xFF {
+            return None;
+        }
+        let marker = *data.get(pos + 1)?;
+        match marker {
+            // Fill byte.
+            0xFF => {
+                pos += 1;
+                continue;
+            }
+            // Start of scan: everything after is entropy-coded image data.
+            0xDA => break,
+            0x01 | 0xD0..=0xD7 => {
+                out.extend_from_slice(&data[pos..pos + 2]);
+                pos += 2;
+                continue;
+            }
+            _ => {}
+        }
+        let len = u16::from_be_bytes([*data.get(pos + 2)?, *data.get(pos + 3)?]) as usize;
+        let segment = data.get(pos..pos + 2 + len)?;
+        let payload = segment.get(4..)?;
+        match marker {
+            0xE1 => {
+                if let Some(exif) = payload.strip_prefix(b"Exif\0\0") {
+                    orientation = orientation.or(Orientation::from_exif_chunk(exif));
+                }
+            }
+            0xED => {}
+            _ => out.extend_from_slice(segment),
+        }
+        pos += segment.len();
+    }
+    out.extend_from_slice(&data[pos..]);
+
+    if let Some(exif) = orientation_exif(orientation) {
+        let payload = [b"Exif\0\0".as_slice(), &exif].concat();
+        let mut segment = vec![0xFF, 0xE1];
+        segment.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        segment.extend_from_slice(&payload);
+        // After the JFIF header, which has to come first.
+        let at = match out.get(2..6) {
+            Some([0xFF, 0xE0, high, low]) => 4 + u16::from_be_bytes([*high, *low]) as usize,
+            _ => 2,
+        };
+        out.splice(at..at, segment);
+    }
+    Some(out)
+}
+
+fn png_chunk(kind: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut crc = flate2::Crc::new();
+    crc.update(kind);
+    crc.update(data);
+    let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(data);
+    chunk.extend_from_slice(&crc.sum().to_be_bytes());
+    chunk
+}
+
+// Drops eXIf and the text chunks, which is where XMP lives in PNGs.
+fn strip_png(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = data.get(..8)?.to_vec();
+    let mut pos = 8;
+    while pos < data.len() {
+        let len = u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        let chunk = data.get(pos..pos.checked_add(12 + len)?)?;
+        let kind = &chunk[4..8];
+        match kind {
+            b"eXIf" => {
+                let orientation = Orientation::from_exif_chunk(&chunk[8..8 + len]);
+                if let Some(exif) = orientation_exif(orientation) {
+                    out.extend_from_slice(&png_chunk(kind, &exif));
+                }
+            }
+            b"tEXt" | b"zTXt" | b"iTXt" => {}
+            _ => out.extend_from_slice(chunk),
+        }
+        pos += chunk.len();
+    }
+    Some(out)
+}
+
+// Drops the EXIF and XMP chunks and clears their flags in the VP8X header.
+fn strip_webp(data: &[u8]) -> Option<Vec<u8>> {
+    const EXIF_FLAG: u8 = 0x08;
+    const XMP_FLAG: u8 = 0x04;
+    let mut out = data.get(..12)?.to_vec();
+    let mut pos = 12;
+    let mut kept_exif = false;
+    let mut header = None;
+    while pos < data.len() {
+        let len = u32::from_le_bytes(data.get(pos + 4..pos + 8)?.try_into().ok()?) as usize;
+        // The padding byte may be missing from the last chunk.
+        let end = pos.checked_add(8 + len)?;
+        data.get(pos..end)?;
+        let end = (end + len % 2).min(data.len());
+        let chunk = &data[pos..end];
+        let kind = &chunk[..4];
+        match kind {
+            b"EXIF" => {
+                let exif = chunk.get(8..8 + len)?;
+                let exif = exif.strip_prefix(b"Exif\0\0").unwrap_or(exif);
+                if let Some(exif) = orientation_exif(Orientation::from_exif_chunk(exif)) {
+                    out.extend_from_slice(kind);
+                    out.extend_from_slice(&(exif.len() as u32).to_le_bytes());
+                    out.extend_from_slice(&exif);
+                    kept_exif = true;
+                }
+            }
+            b"XMP " => {}
+            _ => {
+                if kind == b"VP8X" {
+                    header = Some(out.len() + 8);
+                }
+                out.extend_from_slice(chunk);
+            }
+        }
+        pos = end;
+    }
+    if let Some(flags) = header.and_then(|at| out.get_mut(at)) {
+        *flags &= !XMP_FLAG;
+        if !kept_exif {
+            *flags &= !EXIF_FLAG;
+        }
+    }
+    let riff_size = (out.len() - 8) as u32;
+    out[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageEncoder, RgbImage, codecs::webp::WebPEncoder};
+
+    const CAMERA: &[u8] = b"Cam\0";
+    const XMP: &[u8] = b"http://ns.adobe.com/xap/1.0/\0<x:xmpmeta/>";
+
+    // A TIFF block with the orientation and a camera make, like a phone's.
+    fn exif(orientation: u8) -> Vec<u8> {
+        let mut tiff = b"MM\0\x2a".to_vec();
+        tiff.extend_from_slice(&8u32.to_be_bytes());
+        tiff.extend_from_slice(&2u16.to_be_bytes());
+        tiff.extend_from_slice(&[0x01, 0x0F, 0, 2, 0, 0, 0, 4]);
+        tiff.extend_from_slice(CAMERA);
+        tiff.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, orientation, 0, 0]);
+        tiff.extend_from_slice(&0u32.to_be_bytes());
+        tiff
+    }
+
+    // 8x4 pixels, so a 90 degree orientation shows up in the dimensions.
+    fn image() -> RgbImage {
+        RgbImage::from_fn(8, 4, |x, y| image::Rgb([x as u8 * 30, y as u8 * 60, 90]))
+    }
+
+    fn encode(format: ImageFormat) -> Vec<u8> {
+        let mut data = Vec::new();
+        image()
+            .write_to(&mut Cursor::new(&mut data), format)
+            .unwrap();
+        data
+    }
+
+    fn jpeg_segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xFF, marker];
+        segment.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    fn contains(data: &[u8], needle: &[u8]) -> bool {
+        data.windows(needle.len()).any(|window| window == needle)
+    }
+
+    fn dimensions(data: &[u8]) -> (u32, u32) {
+        let info = inspect(data, 320).unwrap();
+        (info.width, info.height)
+    }
+
+    #[test]
+    fn jpeg_keeps_only_the_orientation() {
+        let plain = encode(ImageFormat::Jpeg);
+        assert_eq!(&plain[2..4], &[0xFF, 0xE0]);
+        assert!(strip_metadata(&plain).unwrap().is_none());
+
+        let jfif_end = 4 + u16::from_be_bytes([plain[4], plain[5]]) as usize;
+        let mut data = plain[..jfif_end].to_vec();
+        data.extend(jpeg_segment(
+            0xE1,
+            &[b"Exif\0\0".as_slice(), &exif(6)].concat(),
+        ));
+        data.extend(jpeg_segment(0xE1, XMP));
+        data.extend(jpeg_segment(0xED, b"Photoshop 3.0\0"));
+        data.extend_from_slice(&plain[jfif_end..]);
+        assert_eq!(dimensions(&data), (4, 8));
+
+        let stripped = strip_metadata(&data).unwrap().unwrap();
+        assert!(!contains(&stripped, CAMERA));
+        assert!(!contains(&stripped, b"xmpmeta"));
+        assert!(!contains(&stripped, b"Photoshop"));
+        // JFIF stays first, with the orientation right after it.
+        assert_eq!(&stripped[..jfif_end], &plain[..jfif_end]);
+        assert_eq!(&stripped[jfif_end..jfif_end + 2], &[0xFF, 0xE1]);
+        assert_eq!(dimensions(&stripped), (4, 8));
+    }
+
+    #[test]
+    fn jpeg_without_orientation_loses_all_exif() {
+        let plain = encode(ImageFormat::Jpeg);
+        let mut data = plain[..2].to_vec();
+        data.extend(jpeg_segment(
+            0xE1,
+            &[b"Exif\0\0".as_slice(), &exif(1)].concat(),
+        ));
+        data.extend_from_slice(&plain[2..]);
+        assert_eq!(strip_metadata(&data).unwrap().unwrap(), plain);
+    }
+
+    #[test]
+    fn png_drops_text_and_keeps_the_orientation() {
+        let plain = encode(ImageFormat::Png);
+        assert!(strip_metadata(&plain).unwrap().is_none());
+
+        // After the signature and IHDR.
+        let at = 8 + 25;
+        let mut data = plain[..at].to_vec();
+        data.extend(png_chunk(b"tEXt", b"Comment\0hello"));
+        data.extend(png_chunk(b"iTXt", XMP));
+        data.extend(png_chunk(b"eXIf", &exif(6)));
+        data.extend_from_slice(&plain[at..]);
+
+        let stripped = strip_metadata(&data).unwrap().unwrap();
+        assert!(!contains(&stripped, CAMERA));
+        assert!(!contains(&stripped, b"tEXt"));
+        assert!(!contains(&stripped, b"xmpmeta"));
+        let at = stripped.windows(4).position(|w| w == b"eXIf").unwrap();
+        let len = u32::from_be_bytes(stripped[at - 4..at].try_into().unwrap()) as usize;
+        assert_eq!(
+            Orientation::from_exif_chunk(&stripped[at + 4..at + 4 + len]),
+            Some(Orientation::Rotate90)
+        );
+        // The decoder checks CRCs, so rewritten chunks have to be valid.
+        assert_eq!(dimensions(&stripped), (4, 8));
+    }
+
+    #[test]
+    fn webp_clears_the_metadata_flags() {
+        let mut lossless = Vec::new();
+        WebPEncoder::new_lossless(&mut lossless)
+            .write_image(image().as_raw(), 8, 4, image::ExtendedColorType::Rgb8)
+            .unwrap();
+        let bitstream = &lossless[12..];
+
+        let mut vp8x = vec![0x08 | 0x04, 0, 0, 0];
+        vp8x.extend_from_slice(&7u32.to_le_bytes()[..3]);
+        vp8x.extend_from_slice(&3u32.to_le_bytes()[..3]);
+        let chunk = |kind: &[u8], payload: &[u8]| {
+            let mut chunk = kind.to_vec();
+            chunk.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            chunk.extend_from_slice(payload);
+            if payload.len() % 2 == 1 {
+                chunk.push(0);
+            }
+            chunk
+        };
+        let mut body = b"WEBP".to_vec();
+        body.extend(chunk(b"VP8X", &vp8x));
+        body.extend_from_slice(bitstream);
+        body.extend(chunk(b"EXIF", &exif(6)));
+        body.extend(chunk(b"XMP ", XMP));
+        let mut data = b"RIFF".to_vec();
+        data.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        data.extend(body);
+
+        let stripped = strip_metadata(&data).unwrap().unwrap();
+        assert!(!contains(&stripped, CAMERA));
+        assert!(!contains(&stripped, b"xmpmeta"));
+        assert_eq!(stripped[20] & 0x04, 0);
+        assert_eq!(stripped[20] & 0x08, 0x08);
+        assert_eq!(
+            u32::from_le_bytes(stripped[4..8].try_into().unwrap()) as usize,
+            stripped.len() - 8
+        );
+        assert_eq!(dimensions(&stripped), (4, 8));
+    }
+
+    #[test]
+    fn malformed_images_are_rejected() {
+        let plain = encode(ImageFormat::Jpeg);
+        let exif_segment = jpeg_segment(0xE1, &[b"Exif\0\0".as_slice(), &exif(6)].concat());
+        let mut data = plain[..2].to_vec();
+        data.extend_from_slice(&exif_segment);
+        data.extend_from_slice(&plain[2..]);
+        // Cut off inside the EXIF segment, or before the start of scan.
+        for len in [10, exif_segment.len(), 2 + exif_segment.len() + 4] {
+            assert!(strip_metadata(&data[..len]).is_err(), "{}", len);
+        }
+        // Junk where a marker should be, hiding the EXIF from the parser.
+        let mut junk = plain[..2].to_vec();
+        junk.push(0x00);
+        junk.extend_from_slice(&exif_segment);
+        junk.extend_from_slice(&plain[2..]);
+        assert!(strip_metadata(&junk).is_err());
+
+        let png = encode(ImageFormat::Png);
+        assert!(strip_metadata(&png[..png.len() - 3]).is_err());
+        assert!(strip_metadata(b"RIFF\0\0\0\0WEBPVP8X\xff\0\0\0").is_err());
+        assert!(strip_metadata(b"not an image").is_err());
+        assert!(strip_metadata(&encode(ImageFormat::Gif)).unwrap().is_none());
+    }
+}
@@ -0,0 +1,73 @@
+use crate::encoding::Encoding;
+
+pub const PROTOCOL_VERSION: u32 = 2;
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+// What a connection speaks. Clients pick it with a `Sec-WebSocket-Protocol`
+// of the form `rtc.<encoding>.v<version>` (e.g. `rtc.msgpack.v2`), or with a
+// `?protocol=<version>` query parameter when they can't set subprotocols.
+// Clients that declare nothing get JSON and the oldest supported version.
+#[derive(Clone, Copy, Debug)]
+pub struct Protocol {
+    pub encoding: Encoding,
+    pub version: u32,
+}
+
+impl Default for Protocol {
+    fn default() -> Self {
+        Self {
+            encoding: Encoding::Json,
+            version: MIN_PROTOCOL_VERSION,
+        }
+    }
+}
+
+impl Protocol {
+    pub fn is_supported(version: u32) -> bool {
+        (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version)
+    }
+
+    pub fn subprotocol(&self) -> String {
+        format!("rtc.{}.v{}", self.encoding.name(), self.version)
+    }
+
+    fn parse(subprotocol: &str) -> Option<Self> {
+        let rest = subprotocol.strip_prefix("rtc.")?;
+        let (encoding, version) = rest.rsplit_once(".v")?;
+        Some(Self {
+            encoding: Encoding::from_name(encoding)?,
+            version: version.parse().ok()?,
+        })
+    }
+
+    // Picks the first offered subprotocol this server supports. Returns an
+    // error when the client only offers versions we can no longer speak.
+    pub fn negotiate(offered: Option<&str>, version: Option<u32>) -> Result<Self, String> {
+        if let Some(offered) = offered {
+            let candidates: Vec<Protocol> = offered
+                .split(',')
+                .filter_map(|p| Self::parse(p.trim()))
+                .collect();
+            if let Some(protocol) = candidates.iter().find(|p| Self::is_supported(p.version)) {
+                return Ok(*protocol);
+            }
+            if !candidates.is_empty() {
+                return Err(format!(
+                    "Unsupported protocol version, supported versions are {}-{}",
+                    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+                ));
+            }
+        }
+        match version {
+            Some(version) if !Self::is_supported(version) => Err(format!(
+                "Unsupported protocol version, supported versions are {}-{}",
+                MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            )),
+            Some(version) => Ok(Self {
+                version,
+                ..Default::default()
+            }),
+            None => Ok(Self::default()),
+        }
+    }
+}
@@ -14,7 +14,7 @@ impl Redis {
         Self { conn }
     }
 
-    pub async fn _get<T>(&self, key: &str) -> RedisResult<T>
+    pub async fn get<T>(&self, key: &str) -> RedisResult<T>
     where
         T: for<'de> Deserialize<'de>,
     {
@@ -25,7 +25,7 @@ impl Redis {
         Ok(value)
     }
 
-    pub async fn _set<T>(&self, key: &str, value: &T) -> RedisResult<()>
+    pub async fn set<T>(&self, key: &str, value: &T) -> RedisResult<()>
     where
         T: Serialize,
     {
@@ -36,12 +36,36 @@ impl Redis {
         Ok(())
     }
 
-    pub async fn _del(&self, key: &str) -> RedisResult<()> {
+    pub async fn expire(&self, key: &str, seconds: i64) -> RedisResult<()> {
+        let mut client = self.conn.get_connection()?;
+        let _: () = client.expire(key, seconds)?;
+        Ok(())
+    }
+
+    pub async fn del(&self, key: &str) -> RedisResult<()> {
         let mut client = self.conn.get_connection()?;
         let _: () = client.del(key)?;
         Ok(())
     }
 
+    // Set operations, on plain strings
+    pub async fn sadd(&self, key: &str, member: &str) -> RedisResult<()> {
+        let mut client = self.conn.get_connection()?;
+        let _: () = client.sadd(key, member)?;
+        Ok(())
+    }
+
+    pub async fn srem(&self, key: &str, member: &str) -> RedisResult<()> {
+        let mut client = self.conn.get_connection()?;
+        let _: () = client.srem(key, member)?;
+        Ok(())
+    }
+
+    pub async fn smembers(&self, key: &str) -> RedisResult<Vec<String>> {
+        let mut client = self.conn.get_connection()?;
+        client.smembers(key)
+    }
+
     // List operations
     pub async fn lpush<T>(&self, key: &str, value: &T) -> RedisResult<()>
     where
@@ -54,7 +78,34 @@ impl Redis {
         Ok(())
     }
 
-    pub async fn _rpush<T>(&self, key: &str, value: &T) -> RedisResult<()>
+    // Pushes to the front and drops entries past `cap`, expiring the whole
+    // list `ttl_secs` after the last push.
+    pub async fn lpush_capped<T>(
+        &self,
+        key: &str,
+        value: &T,
+        cap: usize,
+        ttl_secs: i64,
+    ) -> RedisResult<()>
+    where
+        T: Serialize,
+    {
+        let mut client = self.conn.get_connection()?;
+        let json_str = serde_json::to_string(value)
+            .map_err(|_| redis::RedisError::from((redis::ErrorKind::Io, "JSON serialize error")))?;
+        let _: () = redis::pipe()
+            .atomic()
+            .lpush(key, json_str)
+            .ignore()
+            .ltrim(key, 0, cap as isize - 1)
+            .ignore()
+            .expire(key, ttl_secs)
+            .ignore()
+            .query(&mut client)?;
+        Ok(())
+    }
+
+    pub async fn rpush<T>(&self, key: &str, value: &T) -> RedisResult<()>
     where
         T: Serialize,
     {
@@ -81,7 +132,7 @@ impl Redis {
         Ok(result)
     }
 
-    pub async fn _llen(&self, key: &str) -> RedisResult<usize> {
+    pub async fn llen(&self, key: &str) -> RedisResult<usize> {
         let mut client = self.conn.get_connection()?;
         let len: usize = client.llen(key)?;
         Ok(len)
@@ -115,6 +166,28 @@ impl Redis {
     {
         self.lrange(key, 0, -1).await
     }
+    // Reads and deletes a list in one transaction, so nothing pushed in
+    // between is lost.
+    pub async fn take_all<T>(&self, key: &str) -> RedisResult<Vec<T>>
+    where
+        T: for<'de> Deserialize<'de>,
+    {
+        let mut client = self.conn.get_connection()?;
+        let (json_strings,): (Vec<String>,) = redis::pipe()
+            .atomic()
+            .lrange(key, 0, -1)
+            .del(key)
+            .ignore()
+            .query(&mut client)?;
+
+        let mut result = Vec::new();
+        for json_str in json_strings {
+            let value: T = serde_json::from_str(&json_str)
+                .map_err(|_| redis::RedisError::from((redis::ErrorKind::Io, "JSON parse error")))?;
+            result.push(value);
+        }
+        Ok(result)
+    }
     pub async fn lset_delete(&self, key: &str, index: usize) -> RedisResult<()> {
         let mut client = self.conn.get_connection()?;
         let _: () = client.lset(key, index.try_into().unwrap(), "__DELETE__")?;
@@ -0,0 +1,640 @@
+use crate::{
+    handlers::mentions::is_mention_char,
+    types::{Block, Inline},
+};
+
+const MAX_DEPTH: usize = 8;
+const LINK_SCHEMES: [&str; 3] = ["http://", "https://", "mailto:"];
+const HTML_ELEMENTS: &[&str] = &[
+    "a",
+    "abbr",
+    "audio",
+    "b",
+    "base",
+    "blockquote",
+    "body",
+    "br",
+    "button",
+    "canvas",
+    "code",
+    "del",
+    "details",
+    "div",
+    "em",
+    "embed",
+    "form",
+    "frame",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "head",
+    "hr",
+    "html",
+    "i",
+    "iframe",
+    "img",
+    "input",
+    "ins",
+    "kbd",
+    "label",
+    "li",
+    "link",
+    "mark",
+    "meta",
+    "object",
+    "ol",
+    "p",
+    "picture",
+    "pre",
+    "q",
+    "s",
+    "script",
+    "section",
+    "select",
+    "small",
+    "source",
+    "span",
+    "strong",
+    "style",
+    "sub",
+    "summary",
+    "sup",
+    "svg",
+    "table",
+    "tbody",
+    "td",
+    "template",
+    "textarea",
+    "th",
+    "thead",
+    "title",
+    "tr",
+    "u",
+    "ul",
+    "video",
+];
+
+// Parses the markdown subset messages are written in: `**bold**`, `*italic*`
+// or `_italic_`, `` `code` ``, fenced code blocks, `[text](url)` links,
+// `@user` / `@room` mentions and `>` quotes. Backslash escapes punctuation.
+// Unmatched delimiters are kept as text, but headings, images, HTML, nested
+// quotes and links to anything but http(s) or mailto are rejected.
+pub fn parse(text: &str, members: &[String]) -> Result<Vec<Block>, &'static str> {
+    let blocks = parse_blocks(text, members, false)?;
+    if blocks.is_empty() {
+        return Err("Message is empty");
+    }
+    Ok(blocks)
+}
+
+fn is_fence(line: &str) -> bool {
+    line.trim_start().starts_with("```")
+}
+
+fn is_heading(line: &str) -> bool {
+    let hashes = line.trim_start().chars().take_while(|c| *c == '#').count();
+    (1..=6).contains(&hashes)
+        && line.trim_start()[hashes..]
+            .chars()
+            .next()
+            .is_none_or(char::is_whitespace)
+}
+
+fn parse_blocks(text: &str, members: &[String], quoted: bool) -> Result<Vec<Block>, &'static str> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut blocks = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        if line.trim().is_empty() {
+            i += 1;
+        } else if is_fence(line) {
+            let Some(len) = lines[i + 1..].iter().position(|l| is_fence(l)) else {
+                return Err("Code block is not closed");
+            };
+            blocks.push(Block::Code {
+                text: lines[i + 1..i + 1 + len].join("\n"),
+            });
+            i += len + 2;
+        } else if line.starts_with('>') {
+            if quoted {
+                return Err("Nested quotes are not supported");
+            }
+            let inner: Vec<&str> = lines[i..]
+                .iter()
+                .take_while(|l| l.starts_with('>'))
+                .map(|l| l[1..].strip_prefix(' ').unwrap_or(&l[1..]))
+                .collect();
+            i += inner.len();
+            blocks.push(Block::Quote {
+                children: parse_blocks(&inner.join("\n"), members, true)?,
+            });
+        } else {
+            let paragraph: Vec<&str> = lines[i..]
+                .iter()
+                .take_while(|l| !l.trim().is_empty() && !is_fence(l) && !l.starts_with('>'))
+                .copied()
+                .collect();
+            i += paragraph.len();
+            let mut children = Vec::new();
+            for (n, line) in paragraph.into_iter().enumerate() {
+                if is_heading(line) {
+                    return Err("Headings are not supported");
+                }
+                if n > 0 {
+                    children.push(Inline::LineBreak);
+                }
+                children.extend(parse_inlines(line, members, 0, false)?);
+            }
+            blocks.push(Block::Paragraph { children });
+        }
+    }
+    Ok(blocks)
+}
+
+// Byte offset of the closing `delimiter` in `text`, skipping escapes and code
+// spans. A single `*` doesn't close on half of a `**`, and `_` only closes at
+// the end of a word.
+fn find_closing(text: &str, delimiter: &str) -> Option<usize> {
+    let mut i = 0;
+    while i < text.len() {
+        let rest = &text[i..];
+        if let Some(escaped) = rest.strip_prefix('\\') {
+            i += 1 + escaped.chars().next().map_or(0, char::len_utf8);
+            continue;
+        }
+        if delimiter != "`"
+            && let Some(code) = rest.strip_prefix('`')
+            && let Some(end) = code.find('`')
+        {
+            i += end + 2;
+            continue;
+        }
+        if delimiter == "*" && rest.starts_with("**") {
+            i += 2;
+            continue;
+        }
+        let closes = rest.starts_with(delimiter)
+            && (delimiter != "_" || !rest[1..].starts_with(|c: char| c.is_alphanumeric()));
+        if closes {
+            return Some(i);
+        }
+        i += rest.chars().next().map_or(1, char::len_utf8);
+    }
+    None
+}
+
+// `[label](url)` at the start of `text`: the label, the url and the length.
+fn parse_link(text: &str) -> Option<(&str, &str, usize)> {
+    let label_end = 1 + find_closing(&text[1..], "]")?;
+    let rest = text[label_end + 1..].strip_prefix('(')?;
+    let url_end = rest.find(')')?;
+    let url = &rest[..url_end];
+    if label_end == 1 || url.is_empty() || url.contains(char::is_whitespace) {
+        return None;
+    }
+    Some((&text[1..label_end], url, label_end + 2 + url_end + 1))
+}
+
+// An opening tag, closing tag or comment at the start of `text`. Only known
+// element names count, and attributes need values, so comparisons like
+// `a<b and c>d` or generics like `<T>` stay text.
+fn is_html_tag(text: &str) -> bool {
+    let Some(rest) = text.strip_prefix('<') else {
+        return false;
+    };
+    if let Some(comment) = rest.strip_prefix("!--") {
+        return comment.contains("-->");
+    }
+    let (closing, rest) = match rest.strip_prefix('/') {
+        Some(rest) => (true, rest),
+        None => (false, rest),
+    };
+    let name_len = rest
+        .find(|c: char| !c.is_ascii_alphanumeric())
+        .unwrap_or(rest.len());
+    if !HTML_ELEMENTS
+        .iter()
+        .any(|element| element.eq_ignore_ascii_case(&rest[..name_len]))
+    {
+        return false;
+    }
+    let mut rest = &rest[name_len..];
+    loop {
+        let trimmed = rest.trim_start();
+        if trimmed.starts_with('>') || (!closing && trimmed.starts_with("/>")) {
+            return true;
+        }
+        // Attributes are separated by whitespace, and closing tags have none.
+        if closing || trimmed.len() == rest.len() {
+            return false;
+        }
+        let name_len = trimmed
+            .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | ':')))
+            .unwrap_or(trimmed.len());
+        let Some(value) = trimmed[name_len..].trim_start().strip_prefix('=') else {
+            return false;
+        };
+        let value = value.trim_start();
+        let value_len = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => match value[1..].find(quote) {
+                Some(end) => end + 2,
+                None => return false,
+            },
+            _ => value
+                .find(|c: char| {
+                    c.is_whitespace() || matches!(c, '>' | '"' | '\'' | '<' | '=' | '`')
+                })
+                .unwrap_or(value.len()),
+        };
+        if name_len == 0 || value_len == 0 {
+            return false;
+        }
+        rest = &value[value_len..];
+    }
+}
+
+// Flushes pending text before a formatted node.
+fn push(nodes: &mut Vec<Inline>, plain: &mut String, node: Inline) {
+    if !plain.is_empty() {
+        nodes.push(Inline::Text {
+            text: std::mem::take(plain),
+        });
+    }
+    nodes.push(node);
+}
+
+fn parse_inlines(
+    text: &str,
+    members: &[String],
+    depth: usize,
+    in_link: bool,
+) -> Result<Vec<Inline>, &'static str> {
+    if depth > MAX_DEPTH {
+        return Err("Formatting is nested too deeply");
+    }
+    let mut nodes = Vec::new();
+    let mut plain = String::new();
+    let mut previous: Option<char> = None;
+    let mut i = 0;
+    while let Some(c) = text[i..].chars().next() {
+        let rest = &text[i..];
+        let after = &rest[c.len_utf8()..];
+        let opens = |delimiter: &str| {
+            let inner = &rest[delimiter.len()..];
+            !inner.starts_with(char::is_whitespace)
+                && find_closing(inner, delimiter).is_some_and(|end| end > 0)
+        };
+
+        if c == '\\' && after.starts_with(|c: char| c.is_ascii_punctuation()) {
+            plain.push_str(&after[..1]);
+            i += 2;
+        } else if c == '`' && after.find('`').is_some_and(|end| end > 0) {
+            let end = after.find('`').unwrap();
+            push(
+                &mut nodes,
+                &mut plain,
+                Inline::Code {
+                    text: after[..end].to_owned(),
+                },
+            );
+            i += end + 2;
+        } else if rest.starts_with("**") && opens("**") {
+            let inner = &rest[2..];
+            let end = find_closing(inner, "**").unwrap();
+            let children = parse_inlines(&inner[..end], members, depth + 1, in_link)?;
+            push(&mut nodes, &mut plain, Inline::Bold { children });
+            i += end + 4;
+        } else if (c == '*' || (c == '_' && !previous.is_some_and(char::is_alphanumeric)))
+            && opens(&rest[..1])
+        {
+            let end = find_closing(after, &rest[..1]).unwrap();
+            let children = parse_inlines(&after[..end], members, depth + 1, in_link)?;
+            push(&mut nodes, &mut plain, Inline::Italic { children });
+            i += end + 2;
+        } else if c == '!' && after.starts_with('[') && parse_link(after).is_some() {
+            return Err("Images are not supported");
+        } else if c == '['
+            && !in_link
+            && let Some((label, url, len)) = parse_link(rest)
+        {
+            let lower = url.to_lowercase();
+            if !LINK_SCHEMES
+                .iter()
+                .any(|scheme| lower.starts_with(scheme) && lower.len() > scheme.len())
+            {
+                return Err("Only http, https and mailto links are allowed");
+            }
+            let children = parse_inlines(label, members, depth + 1, true)?;
+            push(
+                &mut nodes,
+                &mut plain,
+                Inline::Link {
+                    url: url.to_owned(),
+                    children,
+                },
+            );
+            i += len;
+        } else if c == '<' && is_html_tag(rest) {
+            return Err("HTML is not supported");
+        } else if c == '@' && !previous.is_some_and(is_mention_char) {
+            let end = after.find(|c| !is_mention_char(c)).unwrap_or(after.len());
+            let name = &after[..end];
+            if name == "room" {
+                push(&mut nodes, &mut plain, Inline::RoomMention);
+            } else if members.iter().any(|m| m == name) {
+                push(
+                    &mut nodes,
+                    &mut plain,
+                    Inline::Mention {
+                        user: name.to_owned(),
+                    },
+                );
+            } else {
+                plain.push('@');
+                plain.push_str(name);
+            }
+            i += 1 + end;
+        } else {
+            plain.push(c);
+            i += c.len_utf8();
+        }
+        previous = text[..i].chars().next_back();
+    }
+    if !plain.is_empty() {
+        nodes.push(Inline::Text { text: plain });
+    }
+    Ok(nodes)
+}
+
+// The plain-text fallback stored in `message`, for search, notifications and
+// clients that don't render rich text.
+pub fn plain_text(blocks: &[Block]) -> String {
+    blocks
+        .iter()
+        .map(|block| match block {
+            Block::Paragraph { children } => inline_text(children),
+            Block::Quote { children } => plain_text(children)
+                .lines()
+                .map(|line| format!("> {}", line))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            Block::Code { text } => text.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+fn inline_text(inlines: &[Inline]) -> String {
+    let mut text = String::new();
+    for inline in inlines {
+        match inline {
+            Inline::Text { text: t } | Inline::Code { text: t } => text.push_str(t),
+            Inline::Bold { children } | Inline::Italic { children } => {
+                text.push_str(&inline_text(children))
+            }
+            Inline::Link { url, children } => {
+                let label = inline_text(children);
+                if label == *url {
+                    text.push_str(url);
+                } else {
+                    text.push_str(&format!("{} ({})", label, url));
+                }
+            }
+            Inline::Mention { user } => {
+                text.push('@');
+                text.push_str(user);
+            }
+            Inline::RoomMention => text.push_str("@room"),
+            Inline::LineBreak => text.push('\n'),
+        }
+    }
+    text
+}
+
+// Members mentioned in the content and whether `@room` was used.
+pub fn mentions(blocks: &[Block]) -> (Vec<String>, bool) {
+    fn walk(inlines: &[Inline], mentions: &mut Vec<String>, room: &mut bool) {
+        for inline in inlines {
+            match inline {
+                Inline::Mention { user } if !mentions.contains(user) => mentions.push(user.clone()),
+                Inline::RoomMention => *room = true,
+                Inline::Bold { children }
+                | Inline::Italic { children }
+                | Inline::Link { children, .. } => walk(children, mentions, room),
+                _ => {}
+            }
+        }
+    }
+    fn walk_blocks(blocks: &[Block], mentions: &mut Vec<String>, room: &mut bool) {
+        for block in blocks {
+            match block {
+                Block::Paragraph { children } => walk(children, mentions, room),
+                Block::Quote { children } => walk_blocks(children, mentions, room),
+                Block::Code { .. } => {}
+            }
+        }
+    }
+    let mut mentions = Vec::new();
+    let mut room = false;
+    walk_blocks(blocks, &mut mentions, &mut room);
+    (mentions, room)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(text: &str) -> Inline {
+        Inline::Text {
+            text: text.to_owned(),
+        }
+    }
+
+    fn italic(children: Vec<Inline>) -> Inline {
+        Inline::Italic { children }
+    }
+
+    fn bold(children: Vec<Inline>) -> Inline {
+        Inline::Bold { children }
+    }
+
+    // The inlines of a message that parses to a single paragraph.
+    fn inlines(message: &str) -> Vec<Inline> {
+        match parse(message, &["alice".to_owned()]).unwrap().as_slice() {
+            [Block::Paragraph { children }] => children.clone(),
+            other => panic!("expected one paragraph, got {:?}", other),
+        }
+    }
+
+    fn rejects(message: &str) -> &'static str {
+        parse(message, &[]).unwrap_err()
+    }
+
+    #[test]
+    fn underscores_only_work_at_word_boundaries() {
+        assert_eq!(
+            inlines("_hi_ there"),
+            vec![italic(vec![text("hi")]), text(" there")]
+        );
+        assert_eq!(inlines("snake_case_name"), vec![text("snake_case_name")]);
+        assert_eq!(
+            inlines("_snake_case_"),
+            vec![italic(vec![text("snake_case")])]
+        );
+    }
+
+    #[test]
+    fn double_and_single_stars() {
+        assert_eq!(inlines("**bold**"), vec![bold(vec![text("bold")])]);
+        assert_eq!(inlines("*it*"), vec![italic(vec![text("it")])]);
+        assert_eq!(
+            inlines("*a **b** c*"),
+            vec![italic(vec![text("a "), bold(vec![text("b")]), text(" c")])]
+        );
+        assert_eq!(inlines("2 * 3 * 4"), vec![text("2 * 3 * 4")]);
+        assert_eq!(inlines("**open"), vec![text("**open")]);
+    }
+
+    #[test]
+    fn escapes_and_code_are_literal() {
+        assert_eq!(inlines(r"\*not italic\*"), vec![text("*not italic*")]);
+        assert_eq!(inlines(r"a\b"), vec![text(r"a\b")]);
+        assert_eq!(
+            inlines("`*x*` and *y*"),
+            vec![
+                Inline::Code {
+                    text: "*x*".to_owned()
+                },
+                text(" and "),
+                italic(vec![text("y")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn links() {
+        assert_eq!(
+            inlines("see [the **docs**](https://example.com)"),
+            vec![
+                text("see "),
+                Inline::Link {
+                    url: "https://example.com".to_owned(),
+                    children: vec![text("the "), bold(vec![text("docs")])],
+                },
+            ]
+        );
+        assert_eq!(inlines("[no url]()"), vec![text("[no url]()")]);
+        assert_eq!(
+            rejects("[x](javascript:alert(1))"),
+            "Only http, https and mailto links are allowed"
+        );
+        assert_eq!(
+            rejects("![cat](https://example.com/cat.png)"),
+            "Images are not supported"
+        );
+    }
+
+    #[test]
+    fn quotes_and_code_blocks() {
+        assert_eq!(
+            parse("> quoted\n> *line*\n\nafter", &[]).unwrap(),
+            vec![
+                Block::Quote {
+                    children: vec![Block::Paragraph {
+                        children: vec![
+                            text("quoted"),
+                            Inline::LineBreak,
+                            italic(vec![text("line")])
+                        ],
+                    }],
+                },
+                Block::Paragraph {
+                    children: vec![text("after")],
+                },
+            ]
+        );
+        assert_eq!(
+            parse("```\n**raw**\n```", &[]).unwrap(),
+            vec![Block::Code {
+                text: "**raw**".to_owned()
+            }]
+        );
+        assert_eq!(rejects("> > nested"), "Nested quotes are not supported");
+        assert_eq!(rejects("```\nopen"), "Code block is not closed");
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        assert!(parse_inlines("*x*", &[], MAX_DEPTH - 1, false).is_ok());
+        assert_eq!(
+            parse_inlines("*x*", &[], MAX_DEPTH, false).unwrap_err(),
+            "Formatting is nested too deeply"
+        );
+        assert_eq!(
+            inlines("**a *b _c_ [d](https://e.com)* f**"),
+            vec![bold(vec![
+                text("a "),
+                italic(vec![
+                    text("b "),
+                    italic(vec![text("c")]),
+                    text(" "),
+                    Inline::Link {
+                        url: "https://e.com".to_owned(),
+                        children: vec![text("d")],
+                    },
+                ]),
+                text(" f"),
+            ])]
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_markdown() {
+        assert_eq!(rejects("# Title"), "Headings are not supported");
+        assert_eq!(rejects(" \n\n "), "Message is empty");
+        assert_eq!(rejects("<b>hi</b>"), "HTML is not supported");
+        assert_eq!(
+            rejects("<img src=x onerror=alert(1)>"),
+            "HTML is not supported"
+        );
+        assert_eq!(rejects("</div >"), "HTML is not supported");
+        assert_eq!(
+            rejects("<a href=\"https://example.com\">"),
+            "HTML is not supported"
+        );
+        assert_eq!(rejects("<!-- hidden -->"), "HTML is not supported");
+        assert_eq!(inlines("#hashtag"), vec![text("#hashtag")]);
+    }
+
+    #[test]
+    fn angle_brackets_that_are_not_tags() {
+        for message in [
+            "if a<b and c>d",
+            "use <T> here",
+            "a <= b",
+            "<3",
+            "Vec<String>",
+        ] {
+            assert_eq!(inlines(message), vec![text(message)], "{}", message);
+        }
+    }
+
+    #[test]
+    fn plain_text_and_mentions() {
+        let blocks = parse(
+            "hi @alice and @bob, @room: [docs](https://example.com)\n> **note**",
+            &["alice".to_owned()],
+        )
+        .unwrap();
+        assert_eq!(
+            plain_text(&blocks),
+            "hi @alice and @bob, @room: docs (https://example.com)\n\n> note"
+        );
+        assert_eq!(mentions(&blocks), (vec!["alice".to_owned()], true));
+        assert_eq!(inlines("mail@alice.com"), vec![text("mail@alice.com")],);
+    }
+}
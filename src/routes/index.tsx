@@ -166,6 +166,23 @@ function App() {
 								),
 							);
 							break;
+						case "room_deleted":
+							setAvailableRooms(
+								(prev) =>
+									prev?.filter((room) => room.room_id !== message.room) ??
+									null,
+							);
+							break;
+						case "room_renamed":
+							setAvailableRooms(
+								(prev) =>
+									prev?.map((room) =>
+										room.room_id === message.room
+											? { ...room, room_name: message.room_name }
+											: room,
+									) ?? null,
+							);
+							break;
 						case "info":
 							setUserId(message.user_id);
 							break;
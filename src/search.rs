@@ -0,0 +1,277 @@
+use std::{
+    ops::Bound,
+    path::Path,
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tantivy::{
+    Index, IndexReader, IndexWriter, ReloadPolicy, TantivyDocument, Term,
+    collector::{Count, TopDocs},
+    directory::MmapDirectory,
+    doc,
+    query::{BooleanQuery, Occur, Query, QueryParser, RangeQuery, TermQuery},
+    schema::{FAST, Field, INDEXED, IndexRecordOption, STORED, STRING, Schema, TEXT, Value},
+    snippet::SnippetGenerator,
+};
+use tracing::error;
+
+use crate::types::RoomMessage;
+
+const WRITER_MEMORY: usize = 50_000_000;
+const COMMIT_INTERVAL: Duration = Duration::from_secs(1);
+const SNIPPET_MAX_CHARS: usize = 200;
+
+#[derive(Clone, Copy)]
+struct Fields {
+    id: Field,
+    room: Field,
+    by: Field,
+    message: Field,
+    seq: Field,
+    sent_at: Field,
+}
+
+pub struct SearchQuery {
+    pub text: String,
+    pub rooms: Vec<String>,
+    pub from: Option<String>,
+    pub before: Option<u64>,
+    pub after: Option<u64>,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchHit {
+    pub room: String,
+    pub message_id: String,
+    pub seq: u64,
+    pub by: String,
+    pub sent_at: u64,
+    pub score: f32,
+    // HTML with matches wrapped in <b>; `fragment` and `highlights` carry the
+    // same information as plain text and byte ranges.
+    pub snippet: String,
+    pub fragment: String,
+    pub highlights: Vec<(usize, usize)>,
+}
+
+enum Write {
+    Index {
+        room: String,
+        message: Box<RoomMessage>,
+    },
+    RemoveMessage(String),
+    RemoveRoom(String),
+    Commit,
+}
+
+// Full-text index over room messages. Writes are queued to a dedicated
+// writer thread, so handlers never wait on tantivy; it commits at most once
+// per `COMMIT_INTERVAL`, so new messages become searchable within about a
+// second.
+#[derive(Clone)]
+pub struct SearchIndex {
+    index: Index,
+    reader: IndexReader,
+    writes: Sender<Write>,
+    fields: Fields,
+}
+
+impl SearchIndex {
+    pub fn open(dir: &Path) -> tantivy::Result<Self> {
+        let mut schema = Schema::builder();
+        let fields = Fields {
+            id: schema.add_text_field("id", STRING | STORED),
+            room: schema.add_text_field("room", STRING | STORED),
+            by: schema.add_text_field("by", STRING | STORED),
+            message: schema.add_text_field("message", TEXT | STORED),
+            seq: schema.add_u64_field("seq", STORED),
+            sent_at: schema.add_u64_field("sent_at", INDEXED | FAST | STORED),
+        };
+        std::fs::create_dir_all(dir)?;
+        let index = Index::open_or_create(MmapDirectory::open(dir)?, schema.build())?;
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::OnCommitWithDelay)
+            .try_into()?;
+        let writer = index.writer(WRITER_MEMORY)?;
+        let (writes, queue) = mpsc::channel();
+        thread::Builder::new()
+            .name("search-writer".into())
+            .spawn(move || write_loop(writer, fields, queue))?;
+        Ok(Self {
+            index,
+            reader,
+            writes,
+            fields,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reader.searcher().num_docs() == 0
+    }
+
+    fn write(&self, write: Write) {
+        if self.writes.send(write).is_err() {
+            error!("Search index writer has stopped");
+        }
+    }
+
+    // Adds or replaces a message; deleted messages are dropped from the index.
+    pub fn index_message(&self, room: &str, message: &RoomMessage) {
+        self.write(Write::Index {
+            room: room.to_owned(),
+            message: Box::new(message.clone()),
+        });
+    }
+
+    pub fn remove_message(&self, id: &str) {
+        self.write(Write::RemoveMessage(id.to_owned()));
+    }
+
+    pub fn remove_room(&self, room: &str) {
+        self.write(Write::RemoveRoom(room.to_owned()));
+    }
+
+    // Commits queued writes without waiting for the next interval.
+    pub fn commit(&self) {
+        self.write(Write::Commit);
+    }
+
+    pub fn search(&self, query: &SearchQuery) -> tantivy::Result<(Vec<SearchHit>, usize)> {
+        let fields = self.fields;
+        let parser = QueryParser::for_index(&self.index, vec![fields.message]);
+        let (text, _) = parser.parse_query_lenient(&query.text);
+
+        let rooms: Vec<(Occur, Box<dyn Query>)> = query
+            .rooms
+            .iter()
+            .map(|room| {
+                let term = Term::from_field_text(fields.room, room);
+                let room: Box<dyn Query> = Box::new(TermQuery::new(term, IndexRecordOption::Basic));
+                (Occur::Should, room)
+            })
+            .collect();
+        let mut clauses: Vec<(Occur, Box<dyn Query>)> = vec![
+            (Occur::Must, text.box_clone()),
+            (Occur::Must, Box::new(BooleanQuery::new(rooms))),
+        ];
+        if let Some(from) = &query.from {
+            clauses.push((
+                Occur::Must,
+                Box::new(TermQuery::new(
+                    Term::from_field_text(fields.by, from),
+                    IndexRecordOption::Basic,
+                )),
+            ));
+        }
+        if query.before.is_some() || query.after.is_some() {
+            let bound = |value: Option<u64>| match value {
+                Some(value) => Bound::Excluded(Term::from_field_u64(fields.sent_at, value)),
+                None => Bound::Unbounded,
+            };
+            clauses.push((
+                Occur::Must,
+                Box::new(RangeQuery::new(bound(query.after), bound(query.before))),
+            ));
+        }
+        let combined = BooleanQuery::new(clauses);
+
+        let searcher = self.reader.searcher();
+        let (top, total) = searcher.search(
+            &combined,
+            &(
+                TopDocs::with_limit(query.limit).and_offset(query.offset),
+                Count,
+            ),
+        )?;
+        let mut snippets = SnippetGenerator::create(&searcher, &*text, fields.message)?;
+        snippets.set_max_num_chars(SNIPPET_MAX_CHARS);
+
+        let mut hits = Vec::new();
+        for (score, address) in top {
+            let doc: TantivyDocument = searcher.doc(address)?;
+            let text_of = |field| {
+                doc.get_first(field)
+                    .and_then(|v| v.as_str())
+                    .unwrap_or_default()
+                    .to_owned()
+            };
+            let number_of = |field| doc.get_first(field).and_then(|v| v.as_u64()).unwrap_or(0);
+            let snippet = snippets.snippet(&text_of(fields.message));
+            hits.push(SearchHit {
+                room: text_of(fields.room),
+                message_id: text_of(fields.id),
+                seq: number_of(fields.seq),
+                by: text_of(fields.by),
+                sent_at: number_of(fields.sent_at),
+                score,
+                snippet: snippet.to_html(),
+                fragment: snippet.fragment().to_owned(),
+                highlights: snippet
+                    .highlighted()
+                    .iter()
+                    .map(|range| (range.start, range.end))
+                    .collect(),
+            });
+        }
+        Ok((hits, total))
+    }
+}
+
+fn apply(writer: &IndexWriter, fields: Fields, write: Write) -> tantivy::Result<()> {
+    match write {
+        Write::Index { room, message } => {
+            writer.delete_term(Term::from_field_text(fields.id, &message.id));
+            if !message.deleted {
+                writer.add_document(doc!(
+                    fields.id => message.id.as_str(),
+                    fields.room => room.as_str(),
+                    fields.by => message.by.as_str(),
+                    fields.message => message.message.as_str(),
+                    fields.seq => message.seq,
+                    fields.sent_at => message.sent_at,
+                ))?;
+            }
+        }
+        Write::RemoveMessage(id) => {
+            writer.delete_term(Term::from_field_text(fields.id, &id));
+        }
+        Write::RemoveRoom(room) => {
+            writer.delete_term(Term::from_field_text(fields.room, &room));
+        }
+        Write::Commit => {}
+    }
+    Ok(())
+}
+
+// Runs on the writer thread until every `SearchIndex` handle is dropped.
+fn write_loop(mut writer: IndexWriter, fields: Fields, queue: Receiver<Write>) {
+    let mut dirty = false;
+    let mut last_commit = Instant::now();
+    loop {
+        let write = queue.recv_timeout(COMMIT_INTERVAL);
+        let stopped = matches!(write, Err(RecvTimeoutError::Disconnected));
+        let forced = matches!(write, Ok(Write::Commit));
+        if let Ok(write) = write {
+            match apply(&writer, fields, write) {
+                Ok(()) => dirty = true,
+                Err(e) => error!("Error while updating search index: {:?}", e),
+            }
+        }
+        if dirty && (forced || stopped || last_commit.elapsed() >= COMMIT_INTERVAL) {
+            if let Err(e) = writer.commit() {
+                error!("Error while committing search index: {:?}", e);
+            }
+            dirty = false;
+            last_commit = Instant::now();
+        }
+        if stopped {
+            return;
+        }
+    }
+}
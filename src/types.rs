@@ -1,8 +1,15 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::Instant,
+};
 
 use serde::{Deserialize, Serialize};
 use tokio::sync::{Mutex, mpsc::UnboundedSender};
 use tokio_tungstenite::tungstenite::Message;
+use uuid::Uuid;
+
+use crate::{encoding::Encoding, utils::now};
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
@@ -10,19 +17,158 @@ pub enum ClientMessages {
     #[serde(rename = "join")]
     Join { room: String },
     #[serde(rename = "create_room")]
-    Create { room_name: String },
+    Create {
+        room_name: String,
+        #[serde(default)]
+        lifecycle: RoomLifecycle,
+    },
     #[serde(rename = "info")]
     Info,
     #[serde(rename = "get_rooms")]
     GetRooms,
     #[serde(rename = "send_message")]
-    SendMessageToRoom { message: String, room: String },
+    SendMessageToRoom {
+        #[serde(default)]
+        message: String,
+        room: String,
+        // Markdown subset; replaces `message`, which becomes its plain text.
+        #[serde(default)]
+        content: Option<String>,
+        #[serde(default)]
+        reply_to: Option<String>,
+    },
     #[serde(rename = "list_messages")]
     ListRoomMessages { room: String },
     #[serde(rename = "get_room")]
     RoomDetails { room: String },
     #[serde(rename = "leave_room")]
     LeaveRoom { room: String, user: String },
+    #[serde(rename = "rename_room")]
+    RenameRoom { room: String, room_name: String },
+    #[serde(rename = "archive_room")]
+    ArchiveRoom { room: String },
+    #[serde(rename = "unarchive_room")]
+    UnarchiveRoom { room: String },
+    #[serde(rename = "list_archived_rooms")]
+    ListArchivedRooms,
+    #[serde(rename = "sync_room")]
+    SyncRoom { room: String },
+    #[serde(rename = "update_room")]
+    UpdateRoom {
+        room: String,
+        #[serde(flatten)]
+        update: RoomUpdate,
+    },
+    #[serde(rename = "search_messages")]
+    SearchMessages {
+        #[serde(flatten)]
+        search: MessageSearch,
+    },
+    #[serde(rename = "start_upload")]
+    StartUpload {
+        #[serde(flatten)]
+        upload: UploadRequest,
+    },
+    #[serde(rename = "resume_upload")]
+    ResumeUpload { upload_id: String },
+    #[serde(rename = "cancel_upload")]
+    CancelUpload { upload_id: String },
+    #[serde(rename = "edit_message")]
+    EditMessage {
+        room: String,
+        message_id: String,
+        #[serde(default)]
+        message: String,
+        #[serde(default)]
+        content: Option<String>,
+    },
+    #[serde(rename = "delete_message")]
+    DeleteMessage { room: String, message_id: String },
+    #[serde(rename = "pin_message")]
+    PinMessage {
+        room: String,
+        message_id: String,
+        #[serde(default = "default_true")]
+        pinned: bool,
+    },
+    #[serde(rename = "react")]
+    React {
+        room: String,
+        message_id: String,
+        emoji: String,
+    },
+    #[serde(rename = "unreact")]
+    Unreact {
+        room: String,
+        message_id: String,
+        emoji: String,
+    },
+    #[serde(rename = "list_thread")]
+    ListThread {
+        room: String,
+        root: String,
+        #[serde(default)]
+        after: Option<String>,
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+    #[serde(rename = "direct_message")]
+    DirectMessage { to: String, message: String },
+    #[serde(rename = "list_direct_messages")]
+    ListDirectMessages {
+        with: String,
+        #[serde(default)]
+        before: Option<usize>,
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+    #[serde(rename = "list_conversations")]
+    ListConversations,
+    #[serde(rename = "typing_start")]
+    TypingStart { room: String },
+    #[serde(rename = "typing_stop")]
+    TypingStop { room: String },
+    #[serde(rename = "mark_read")]
+    MarkRead { room: String, up_to_seq: u64 },
+    #[serde(rename = "set_read_receipts")]
+    SetReadReceipts { room: String, enabled: bool },
+    #[serde(rename = "list_mentions")]
+    ListMentions {
+        #[serde(default)]
+        offset: Option<usize>,
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+    #[serde(rename = "set_profile")]
+    SetProfile {
+        #[serde(default)]
+        display_name: Option<String>,
+        #[serde(default)]
+        avatar: Option<String>,
+        #[serde(default)]
+        status: Option<String>,
+    },
+    #[serde(rename = "get_profiles")]
+    GetProfiles { users: Vec<String> },
+    #[serde(rename = "set_presence")]
+    SetPresence { state: PresenceState },
+    #[serde(rename = "get_presence")]
+    GetPresence { users: Vec<String> },
+    #[serde(rename = "subscribe_room_list")]
+    SubscribeRoomList {
+        #[serde(default)]
+        filter: RoomListFilter,
+        #[serde(default)]
+        sort: RoomListSort,
+        limit: Option<usize>,
+        cursor: Option<usize>,
+    },
+    #[serde(rename = "unsubscribe_room_list")]
+    UnsubscribeRoomList,
+}
+
+fn default_true() -> bool {
+    true
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -32,12 +178,509 @@ pub struct Room {
     pub messages: Vec<RoomMessage>,
     pub users: Vec<String>,
     pub admin: String,
+    #[serde(default)]
+    pub last_seq: u64,
+    #[serde(default)]
+    pub read_receipts: bool,
+    #[serde(default)]
+    pub read_positions: HashMap<String, u64>,
+    // Bumped on every membership, admin or name change so clients applying
+    // deltas can spot a gap and resync.
+    #[serde(default)]
+    pub version: u64,
+    #[serde(default)]
+    pub topic: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub created_at: u64,
+    #[serde(default)]
+    pub last_activity_at: u64,
+    // Free-form application data, e.g. game or meeting settings.
+    #[serde(default)]
+    pub attributes: serde_json::Map<String, serde_json::Value>,
+    // Rooms stored before lifecycles existed were never meant to expire.
+    #[serde(default = "RoomLifecycle::stored_default")]
+    pub lifecycle: RoomLifecycle,
+    // Archived rooms are read-only and hidden from room lists.
+    #[serde(default)]
+    pub archived_at: Option<u64>,
+    // Everyone who has left, so they can still read an archived room.
+    #[serde(default)]
+    pub former_users: Vec<String>,
+    // Overrides the server's default message retention.
+    #[serde(default)]
+    pub retention: Option<MessageRetention>,
+    // Seq of the oldest message still kept; everything before it was pruned.
+    #[serde(default)]
+    pub history_start: u64,
+}
+
+// How many messages a room keeps, by count and/or age. Unset limits don't apply.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MessageRetention {
+    #[serde(default)]
+    pub max_count: Option<usize>,
+    #[serde(default)]
+    pub max_age_hours: Option<u64>,
+}
+
+// When the reaper may delete a room.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RoomLifecycle {
+    // Deleted once no member has been connected for the grace period.
+    #[default]
+    Ephemeral,
+    // Never deleted by the reaper.
+    Persistent,
+    // Deleted this many hours after the last activity.
+    Ttl {
+        hours: u64,
+    },
+}
+
+impl RoomLifecycle {
+    fn stored_default() -> Self {
+        Self::Persistent
+    }
+}
+
+impl Room {
+    pub fn is_moderator(&self, user: &str) -> bool {
+        self.admin == user
+    }
+
+    pub fn is_archived(&self) -> bool {
+        self.archived_at.is_some()
+    }
+
+    pub fn can_read(&self, user: &str) -> bool {
+        !self.is_archived()
+            || self
+                .users
+                .iter()
+                .chain(&self.former_users)
+                .any(|u| u == user)
+    }
+
+    pub fn unread_count(&self, user: &str) -> usize {
+        let read = self.read_positions.get(user).copied().unwrap_or(0);
+        self.messages
+            .iter()
+            .filter(|m| m.seq > read && m.by != user && !m.deleted)
+            .count()
+    }
+
+    // What a client is allowed to see: tombstones without their history, and
+    // read positions only when the room has opted in to receipts.
+    pub fn public(&self) -> Self {
+        let mut room = self.clone();
+        room.messages = room.messages.iter().map(|m| m.redacted()).collect();
+        if !room.read_receipts {
+            room.read_positions.clear();
+        }
+        room
+    }
+
+    // Rooms stored before last_activity_at existed fall back to their last message.
+    pub fn last_activity(&self) -> u64 {
+        self.messages
+            .last()
+            .map_or(0, |m| m.sent_at)
+            .max(self.last_activity_at)
+    }
+
+    // One row of a room list.
+    pub fn summary(&self) -> serde_json::Value {
+        serde_json::json!({
+            "room":self.room,
+            "room_name":self.room_name,
+            "topic":self.topic,
+            "tags":self.tags,
+            "members":self.users.len(),
+            "last_activity":self.last_activity(),
+            "version":self.version
+        })
+    }
+
+    // Room state without its history, as sent on resync.
+    pub fn state(&self) -> serde_json::Value {
+        serde_json::json!({
+            "room":self.room,
+            "room_name":self.room_name,
+            "admin":self.admin,
+            "users":self.users,
+            "last_seq":self.last_seq,
+            "read_receipts":self.read_receipts,
+            "topic":self.topic,
+            "description":self.description,
+            "tags":self.tags,
+            "created_at":self.created_at,
+            "last_activity":self.last_activity(),
+            "attributes":self.attributes,
+            "lifecycle":self.lifecycle,
+            "archived_at":self.archived_at,
+            "retention":self.retention,
+            "history_start":self.history_start,
+            "version":self.version
+        })
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct RoomMessage {
+    #[serde(default)]
+    pub id: String,
+    #[serde(default)]
+    pub seq: u64,
     pub by: String,
     pub message: String,
+    #[serde(default)]
+    pub sent_at: u64,
+    #[serde(default)]
+    pub edited_at: Option<u64>,
+    #[serde(default)]
+    pub deleted: bool,
+    #[serde(default)]
+    pub deleted_by: Option<String>,
+    #[serde(default)]
+    pub pinned: bool,
+    #[serde(default)]
+    pub history: Vec<MessageRevision>,
+    #[serde(default)]
+    pub reactions: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    pub reply_to: Option<String>,
+    #[serde(default)]
+    pub thread_root: Option<String>,
+    #[serde(default)]
+    pub thread: Option<ThreadSummary>,
+    #[serde(default)]
+    pub mentions: Vec<String>,
+    #[serde(default)]
+    pub mentions_room: bool,
+    #[serde(default)]
+    pub attachment: Option<Attachment>,
+    // Formatted body; `message` then holds its plain-text rendering.
+    #[serde(default)]
+    pub content: Option<Vec<Block>>,
+}
+
+// A file stored in the blob store, referenced by the hex SHA-256 of its content.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Attachment {
+    pub blob_id: String,
+    pub name: String,
+    pub size: u64,
+    pub mime: String,
+    // Images only; dimensions are as displayed, after EXIF orientation.
+    #[serde(default)]
+    pub width: Option<u32>,
+    #[serde(default)]
+    pub height: Option<u32>,
+    // Absent when the image is already small enough to show as is.
+    #[serde(default)]
+    pub thumbnail: Option<Thumbnail>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Thumbnail {
+    pub blob_id: String,
+    pub width: u32,
+    pub height: u32,
+    pub mime: String,
+}
+
+// Fields of `start_upload`. `sha256` is the hex digest of the whole file and
+// `message` an optional caption posted with it.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UploadRequest {
+    pub room: String,
+    pub name: String,
+    pub size: u64,
+    pub mime: String,
+    pub sha256: String,
+    #[serde(default)]
+    pub message: String,
+    pub reply_to: Option<String>,
+}
+
+// An attachment being uploaded in chunks. Kept in Redis so a transfer can be
+// resumed from another connection; the bytes received so far are on disk.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Upload {
+    pub id: String,
+    pub user: String,
+    pub room: String,
+    pub name: String,
+    pub size: u64,
+    pub mime: String,
+    pub sha256: String,
+    pub message: String,
+    pub reply_to: Option<String>,
+    pub created_at: u64,
+}
+
+impl RoomMessage {
+    pub fn new(by: String, message: String) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            seq: 0,
+            by,
+            message,
+            sent_at: now(),
+            edited_at: None,
+            deleted: false,
+            deleted_by: None,
+            pinned: false,
+            history: vec![],
+            reactions: BTreeMap::new(),
+            reply_to: None,
+            thread_root: None,
+            thread: None,
+            mentions: vec![],
+            mentions_room: false,
+            attachment: None,
+            content: None,
+        }
+    }
+
+    // Deleted messages keep their previous revisions for audit, but clients
+    // only ever see the tombstone.
+    pub fn redacted(&self) -> Self {
+        let mut message = self.clone();
+        if message.deleted {
+            message.history.clear();
+            message.attachment = None;
+            message.content = None;
+        }
+        message
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ThreadSummary {
+    pub reply_count: usize,
+    pub last_reply_at: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MessageRevision {
+    pub message: String,
+    #[serde(default)]
+    pub content: Option<Vec<Block>>,
+    pub edited_at: u64,
+}
+
+// Rich text AST, see `rich_text::parse` for the markdown subset it comes from.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Block {
+    Paragraph {
+        children: Vec<Inline>,
+    },
+    Quote {
+        children: Vec<Block>,
+    },
+    #[serde(rename = "code_block")]
+    Code {
+        text: String,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Inline {
+    Text { text: String },
+    Bold { children: Vec<Inline> },
+    Italic { children: Vec<Inline> },
+    Code { text: String },
+    Link { url: String, children: Vec<Inline> },
+    Mention { user: String },
+    RoomMention,
+    LineBreak,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Profile {
+    pub user_id: String,
+    pub display_name: Option<String>,
+    pub avatar: Option<String>,
+    pub status: Option<String>,
+    #[serde(default)]
+    pub updated_at: u64,
+}
+
+impl Profile {
+    pub fn empty(user_id: &str) -> Self {
+        Self {
+            user_id: user_id.to_owned(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceState {
+    Online,
+    Away,
+    DoNotDisturb,
+    Offline,
+}
+
+pub struct PresenceEntry {
+    pub state: PresenceState,
+    pub manual: Option<PresenceState>,
+    pub last_active: Instant,
+    pub last_persisted: Instant,
+}
+
+impl PresenceEntry {
+    pub fn effective(&self) -> PresenceState {
+        self.manual.unwrap_or(self.state)
+    }
+}
+
+pub type Presences = Arc<Mutex<HashMap<String, PresenceEntry>>>;
+
+// What the mentions inbox stores. The message itself is looked up when the
+// inbox is listed, so edits and deletes show through.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MentionEntry {
+    pub room: String,
+    pub message_id: String,
+    pub seq: u64,
+    pub everyone: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Mention {
+    pub room: String,
+    pub room_name: String,
+    pub message_id: String,
+    pub seq: u64,
+    pub by: String,
+    pub message: String,
+    pub sent_at: u64,
+    pub everyone: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DirectMessage {
+    pub id: String,
+    pub from: String,
+    pub to: String,
+    pub message: String,
+    pub sent_at: u64,
+}
+
+impl DirectMessage {
+    pub fn new(from: String, to: String, message: String) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            from,
+            to,
+            message,
+            sent_at: now(),
+        }
+    }
+
+    pub fn conversation_key(a: &str, b: &str) -> String {
+        if a < b {
+            format!("dm:{}:{}", a, b)
+        } else {
+            format!("dm:{}:{}", b, a)
+        }
+    }
+}
+
+// Every user can be connected from several tabs or devices at once, so each
+// user id maps to its live connections keyed by connection id.
+pub type Devices = HashMap<String, Device>;
+
+pub struct Device {
+    pub tx: UnboundedSender<Message>,
+    // So broadcasts can be encoded once per encoding rather than per device.
+    pub encoding: Encoding,
+}
+
+pub type Connections = Arc<Mutex<HashMap<String, Devices>>>;
+
+pub struct TypingState {
+    pub expires_at: Instant,
+    pub last_sent: Instant,
+}
+
+pub type Typing = Arc<Mutex<HashMap<(String, String), TypingState>>>;
+
+// Fields of `search_messages`. `before` and `after` are exclusive sent_at
+// bounds in unix millis; `from` limits results to one author.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct MessageSearch {
+    pub query: String,
+    pub room: Option<String>,
+    pub from: Option<String>,
+    pub before: Option<u64>,
+    pub after: Option<u64>,
+    pub cursor: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+// Fields of `update_room`; anything left out stays as it is.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RoomUpdate {
+    pub topic: Option<String>,
+    pub description: Option<String>,
+    pub tags: Option<Vec<String>>,
+    pub attributes: Option<serde_json::Map<String, serde_json::Value>>,
+    pub lifecycle: Option<RoomLifecycle>,
+    // `null` goes back to the server's default retention.
+    #[serde(default, deserialize_with = "present")]
+    pub retention: Option<Option<MessageRetention>>,
+}
+
+// Tells a field set to null (`Some(None)`) apart from a missing one (`None`).
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RoomListFilter {
+    // Case-insensitive room name prefix.
+    #[serde(default)]
+    pub prefix: Option<String>,
+    // Only rooms the subscriber is a member of.
+    #[serde(default)]
+    pub joined: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomListSort {
+    #[default]
+    Activity,
+    Members,
+    Name,
+}
+
+// A connection's view of the room list: the query it asked for and the rows
+// it was last sent, so changes can be pushed as diffs against that view.
+pub struct RoomListSubscription {
+    pub user: String,
+    pub tx: UnboundedSender<Message>,
+    pub filter: RoomListFilter,
+    pub sort: RoomListSort,
+    pub limit: usize,
+    pub cursor: usize,
+    pub view: Vec<serde_json::Value>,
 }
 
-pub type Connections = Arc<Mutex<HashMap<String, UnboundedSender<Message>>>>;
+pub type RoomLists = Arc<Mutex<HashMap<String, RoomListSubscription>>>;